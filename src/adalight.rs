@@ -0,0 +1,265 @@
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::os::unix::io::AsRawFd;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use anyhow::*;
+use serde::Deserialize;
+use termios::os::target::{
+    speed_t, B1000000, B115200, B19200, B2000000, B230400, B38400, B460800, B500000, B57600,
+    B921600, B9600,
+};
+use termios::{cfmakeraw, cfsetspeed, tcsetattr, Termios, TCSANOW};
+
+use crate::color::Color;
+use crate::device::{Device, Fan, Shutdown, Strip};
+
+/// A single led strip driven by a microcontroller running an Adalight sketch, such as an
+/// Arduino on a serial port.
+pub struct Adalight {
+    config: AdalightConfig,
+    port: Option<File>,
+    strips: Vec<Strip>,
+    strips_dirty: bool,
+    last_write: Instant,
+    last_attempt: Option<Instant>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct AdalightConfig {
+    pub name: String,
+    /// The serial port, or any other file to write the frames to.
+    pub path: PathBuf,
+    #[serde(default = "default_baud_rate")]
+    pub baud_rate: u32,
+    pub leds: usize,
+}
+
+/// Most sketches turn the leds off when they haven't heard from us for a while.
+const KEEPALIVE: Duration = Duration::from_secs(1);
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(5);
+
+fn default_baud_rate() -> u32 {
+    115200
+}
+
+fn speed(baud_rate: u32) -> Result<speed_t> {
+    Ok(match baud_rate {
+        9600 => B9600,
+        19200 => B19200,
+        38400 => B38400,
+        57600 => B57600,
+        115200 => B115200,
+        230400 => B230400,
+        460800 => B460800,
+        500000 => B500000,
+        921600 => B921600,
+        1000000 => B1000000,
+        2000000 => B2000000,
+        _ => bail!("Unsupported baud rate {}", baud_rate),
+    })
+}
+
+/// A frame is the "Ada" magic word, the number of leds minus one and a checksum, followed by the
+/// colors.
+fn frame(colors: &[Color]) -> Vec<u8> {
+    let [hi, lo] = (colors.len().max(1) as u16 - 1).to_be_bytes();
+    let mut frame = vec![b'A', b'd', b'a', hi, lo, hi ^ lo ^ 0x55];
+    for color in colors.iter() {
+        let [r, g, b] = color.rgb();
+        frame.extend_from_slice(&[(r * 255.0) as u8, (g * 255.0) as u8, (b * 255.0) as u8]);
+    }
+    frame
+}
+
+impl Adalight {
+    pub fn new(config: AdalightConfig) -> Self {
+        Self {
+            strips: vec![Strip {
+                leds: Some(config.leds),
+                ..Strip::default()
+            }],
+            strips_dirty: true,
+            port: None,
+            last_write: Instant::now(),
+            last_attempt: None,
+            config,
+        }
+    }
+
+    fn open(&self) -> Result<File> {
+        let port = OpenOptions::new()
+            .write(true)
+            .open(&self.config.path)
+            .with_context(|| format!("Unable to open {}", self.config.path.display()))?;
+
+        // plain files and pipes have no baud rate to set
+        if let Ok(mut termios) = Termios::from_fd(port.as_raw_fd()) {
+            cfmakeraw(&mut termios);
+            cfsetspeed(&mut termios, speed(self.config.baud_rate)?)?;
+            tcsetattr(port.as_raw_fd(), TCSANOW, &termios)?;
+        }
+
+        Ok(port)
+    }
+
+    fn connect(&mut self) -> Result<()> {
+        self.last_attempt = Some(Instant::now());
+        self.port = Some(self.open()?);
+        Ok(())
+    }
+
+    fn write(&mut self, colors: &[Color]) -> Result<()> {
+        if self.port.is_none() {
+            self.port = Some(self.open()?);
+        }
+        let port = self.port.as_mut().unwrap();
+        if let Err(e) = port.write_all(&frame(colors)) {
+            // open the port again on the next write, in case it went away
+            self.port = None;
+            return Err(e)
+                .with_context(|| format!("Unable to write to {}", self.config.path.display()));
+        }
+
+        self.last_write = Instant::now();
+        Ok(())
+    }
+}
+
+impl Device for Adalight {
+    /// The port may show up later, such as when the microcontroller is plugged in after we start.
+    fn initialize(&mut self) -> Result<()> {
+        if let Err(e) = self.connect() {
+            log::warn!("{}: {}", self.config.name, e);
+        }
+        self.strips_dirty = true;
+        Ok(())
+    }
+
+    fn is_led_only(&self) -> bool {
+        true
+    }
+
+    fn name(&self) -> &str {
+        self.config.name.as_str()
+    }
+
+    fn fans(&mut self) -> &mut [Fan] {
+        &mut []
+    }
+
+    fn strips(&mut self) -> &mut [Strip] {
+        self.strips_dirty = true;
+        &mut self.strips
+    }
+
+    fn probes(&self) -> &[Option<f32>] {
+        &[]
+    }
+
+    fn report_status(&self) {}
+
+    fn update(&mut self) -> Result<()> {
+        if !self.strips_dirty && self.last_write.elapsed() < KEEPALIVE {
+            return Ok(());
+        }
+
+        if self.port.is_none() {
+            if self
+                .last_attempt
+                .is_some_and(|last| last.elapsed() < RECONNECT_INTERVAL)
+            {
+                return Ok(());
+            }
+            self.connect()?;
+        }
+
+        let colors = self.strips[0].render(self.config.leds);
+        self.write(&colors)?;
+        self.strips_dirty = false;
+        Ok(())
+    }
+
+    fn shutdown(&mut self, shutdown: &Shutdown) -> Result<()> {
+        let strip = Strip {
+            colors: vec![],
+            hardware: Some(shutdown.effect(0).clone()),
+            ..Strip::default()
+        };
+        self.write(&strip.render(self.config.leds))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::CStr;
+    use std::io::Read;
+    use std::os::unix::io::FromRawFd;
+
+    use super::*;
+
+    /// The master side of a pseudo terminal, and the path of the side that acts as the port.
+    fn pty() -> (File, PathBuf) {
+        unsafe {
+            let master = libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY);
+            assert!(master >= 0);
+            assert_eq!(libc::grantpt(master), 0);
+            assert_eq!(libc::unlockpt(master), 0);
+            let mut name = [0 as libc::c_char; 128];
+            assert_eq!(libc::ptsname_r(master, name.as_mut_ptr(), name.len()), 0);
+            let path = CStr::from_ptr(name.as_ptr()).to_str().unwrap().into();
+            (File::from_raw_fd(master), path)
+        }
+    }
+
+    fn adalight(path: PathBuf, leds: usize) -> Adalight {
+        Adalight::new(AdalightConfig {
+            name: "Test".to_string(),
+            path,
+            baud_rate: default_baud_rate(),
+            leds,
+        })
+    }
+
+    #[test]
+    fn frame_header() {
+        assert_eq!(
+            frame(&[Color::Rgb(0.0, 0.0, 0.0)])[..6],
+            *b"Ada\x00\x00\x55"
+        );
+        let header = &frame(&vec![Color::Rgb(0.0, 0.0, 0.0); 300])[..6];
+        assert_eq!(header, b"Ada\x01\x2b\x7f");
+    }
+
+    #[test]
+    fn update_writes_frames_to_the_port() {
+        let (mut master, path) = pty();
+        let mut device = adalight(path, 4);
+        device.initialize().unwrap();
+
+        device.strips()[0].colors = vec![Color::Rgb(1.0, 0.0, 0.0), Color::Rgb(0.0, 0.0, 1.0)];
+        device.update().unwrap();
+
+        let mut frame = [0u8; 6 + 4 * 3];
+        master.read_exact(&mut frame).unwrap();
+        assert_eq!(frame[..6], *b"Ada\x00\x03\x56");
+        // the strip is padded with black
+        assert_eq!(frame[6..], [255, 0, 0, 0, 0, 255, 0, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn missing_port_is_tried_again_later() {
+        let path = std::env::temp_dir()
+            .join(format!("fanservice-adalight-{}", std::process::id()))
+            .join("missing");
+        let mut device = adalight(path, 4);
+        device.initialize().unwrap();
+        assert!(device.port.is_none());
+        // not again right away
+        device.update().unwrap();
+
+        device.last_attempt = None;
+        assert!(device.update().is_err());
+    }
+}
@@ -1,6 +1,6 @@
 use serde::Deserialize;
 
-#[derive(Clone, Copy, Deserialize, Debug)]
+#[derive(Clone, Copy, Deserialize, Debug, PartialEq)]
 pub enum Color {
     Rgb(f32, f32, f32),
     Hsv(f32, f32, f32),
@@ -0,0 +1,615 @@
+use anyhow::*;
+
+use crate::color::Color;
+use crate::device::{
+    curve_rpm, stalled, Device, Fan, HardwareEffect, Shutdown, StallCounter, Strip,
+};
+use crate::driver::{Driver, Registry};
+use crate::transport::Transport;
+
+/// The Commander CORE and CORE XT. Unlike the Commander PRO, these don't take commands for
+/// specific settings. Every setting lives in an endpoint that is opened, read and written as a
+/// whole. While we are connected the device is kept awake in software mode, and putting it back
+/// to sleep hands the lights and fans back to its firmware.
+pub struct CommanderCore {
+    name: String,
+    device: Box<dyn Transport>,
+    /// On the Commander CORE the pump is fan channel 0.
+    fans: Vec<Fan>,
+    /// The duty cycle every fan channel was last set to, for rpm targets to start from.
+    duties: Vec<f32>,
+    written: Vec<Option<u8>>,
+    strips: Vec<Strip>,
+    strips_dirty: bool,
+    led_counts: Vec<usize>,
+    probes: Vec<Option<f32>>,
+    rpms: Vec<Option<u16>>,
+    /// Ports without a fan read 0 rpm, so they are left out of the speeds and stall checks.
+    fans_connected: Vec<bool>,
+    stalls: Vec<StallCounter>,
+    /// Speeds and temperatures are read on alternating updates.
+    sample_temps: bool,
+}
+
+const REPORT_LENGTH: usize = 96;
+/// The first bytes of a report are the report id, the 0x08 that every command starts with, and
+/// the command itself.
+const WRITE_HEADER: usize = 4;
+
+const CMD_WAKE: [u8; 4] = [0x01, 0x03, 0x00, 0x02];
+const CMD_SLEEP: [u8; 4] = [0x01, 0x03, 0x00, 0x01];
+const CMD_GET_FIRMWARE: [u8; 2] = [0x02, 0x13];
+const CMD_CLOSE_ENDPOINT: [u8; 3] = [0x05, 0x01, 0x00];
+const CMD_OPEN_ENDPOINT: [u8; 2] = [0x0d, 0x00];
+const CMD_READ: [u8; 2] = [0x08, 0x00];
+const CMD_WRITE: [u8; 2] = [0x06, 0x00];
+const CMD_WRITE_MORE: [u8; 2] = [0x07, 0x00];
+
+/// An endpoint is opened by its address, and holds data of a single type.
+struct Endpoint {
+    address: &'static [u8],
+    data_type: [u8; 2],
+}
+
+const SPEEDS: Endpoint = Endpoint {
+    address: &[0x17],
+    data_type: [0x06, 0x00],
+};
+const CONNECTED_FANS: Endpoint = Endpoint {
+    address: &[0x1a],
+    data_type: [0x09, 0x00],
+};
+const LED_COUNTS: Endpoint = Endpoint {
+    address: &[0x20],
+    data_type: [0x0f, 0x00],
+};
+const TEMPERATURES: Endpoint = Endpoint {
+    address: &[0x21],
+    data_type: [0x10, 0x00],
+};
+const COLORS: Endpoint = Endpoint {
+    address: &[0x22],
+    data_type: [0x12, 0x00],
+};
+const SPEED_MODES: Endpoint = Endpoint {
+    address: &[0x60, 0x6d],
+    data_type: [0x03, 0x00],
+};
+const FIXED_PERCENT: Endpoint = Endpoint {
+    address: &[0x61, 0x6d],
+    data_type: [0x04, 0x00],
+};
+
+const SPEED_MODE_FIXED_PERCENT: u8 = 0x00;
+const LED_PORT_CONNECTED: u16 = 0x02;
+const FAN_CONNECTED: u8 = 0x07;
+const TEMP_CONNECTED: u8 = 0x00;
+
+/// How long to wait for a reply, in milliseconds.
+const READ_TIMEOUT: i32 = 100;
+/// Reports that aren't replies, such as input reports, are skipped up to this many times.
+const MAX_SKIPPED_REPORTS: usize = 8;
+const RPM_CONTROL_GAIN: f32 = 0.1;
+
+pub fn register(registry: &mut Registry) {
+    registry.register(Driver {
+        name: "Commander CORE",
+        vendor_id: 0x1b1c,
+        product_id: 0x0c1c,
+        interface: Some(0),
+        open: |device| Box::new(CommanderCore::new_commander_core(device)),
+    });
+    registry.register(Driver {
+        name: "Commander CORE XT",
+        vendor_id: 0x1b1c,
+        product_id: 0x0c2a,
+        interface: Some(0),
+        open: |device| Box::new(CommanderCore::new_commander_core_xt(device)),
+    });
+}
+
+fn le16(data: &[u8], offset: usize) -> Result<u16> {
+    match data.get(offset..offset + 2) {
+        Some(bytes) => Ok(u16::from_le_bytes([bytes[0], bytes[1]])),
+        None => bail!("Reply of {} bytes is too short", data.len()),
+    }
+}
+
+impl CommanderCore {
+    pub fn new_commander_core(device: impl Transport + 'static) -> Self {
+        Self::new("Commander CORE", device, 7, 1)
+    }
+
+    pub fn new_commander_core_xt(device: impl Transport + 'static) -> Self {
+        Self::new("Commander CORE XT", device, 6, 2)
+    }
+
+    fn new(name: &str, device: impl Transport + 'static, fans: usize, probes: usize) -> Self {
+        Self {
+            name: String::from(name),
+            device: Box::new(device),
+            fans: vec![Fan::Pwm(0.25); fans],
+            duties: vec![0.25; fans],
+            written: vec![None; fans],
+            // the pump ring or the led port, followed by the six fan ports
+            strips: vec![Strip::default(); 7],
+            strips_dirty: true,
+            led_counts: vec![0; 7],
+            probes: vec![None; probes],
+            rpms: vec![None; fans],
+            fans_connected: vec![false; fans],
+            stalls: vec![StallCounter::default(); fans],
+            sample_temps: false,
+        }
+    }
+
+    /// Send a command and return the data of its reply.
+    fn command(&self, command: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+        if command.len() + data.len() > REPORT_LENGTH - 1 {
+            bail!("{} bytes don't fit in a report", command.len() + data.len());
+        }
+        let mut report = [0u8; REPORT_LENGTH + 1];
+        report[1] = 0x08;
+        report[2..2 + command.len()].copy_from_slice(command);
+        report[2 + command.len()..2 + command.len() + data.len()].copy_from_slice(data);
+        self.device.write(&report)?;
+
+        let mut reply = [0u8; REPORT_LENGTH];
+        for _ in 0..MAX_SKIPPED_REPORTS {
+            let len = self.device.read_timeout(&mut reply, READ_TIMEOUT)?;
+            if len == 0 {
+                bail!("No reply to {:02x?}", command);
+            }
+            if reply[0] != 0x00 {
+                continue;
+            }
+            if len < 3 || reply[1] != command[0] {
+                bail!(
+                    "Reply {:02x?} doesn't match {:02x?}",
+                    &reply[..len],
+                    command
+                );
+            }
+            return Ok(reply[3..len].to_vec());
+        }
+        bail!("No reply to {:02x?} among the reports", command)
+    }
+
+    fn open_endpoint(&self, endpoint: &Endpoint) -> Result<()> {
+        self.command(&CMD_CLOSE_ENDPOINT, &[])?;
+        self.command(&CMD_OPEN_ENDPOINT, endpoint.address)?;
+        Ok(())
+    }
+
+    fn read_endpoint(&self, endpoint: &Endpoint) -> Result<Vec<u8>> {
+        self.open_endpoint(endpoint)?;
+        let data = self.command(&CMD_READ, &[])?;
+        if data.get(..2) != Some(&endpoint.data_type[..]) {
+            bail!(
+                "Endpoint {:02x?} holds {:02x?} instead of {:02x?}",
+                endpoint.address,
+                data.get(..2),
+                endpoint.data_type
+            );
+        }
+        Ok(data[2..].to_vec())
+    }
+
+    /// Replace the data of an endpoint. Data that doesn't fit in one report is continued in the
+    /// reports that follow.
+    fn write_endpoint(&self, endpoint: &Endpoint, data: &[u8]) -> Result<()> {
+        self.open_endpoint(endpoint)?;
+
+        let mut payload = Vec::with_capacity(data.len() + 6);
+        payload.extend_from_slice(&(data.len() as u16 + 2).to_le_bytes());
+        payload.extend_from_slice(&[0x00, 0x00]);
+        payload.extend_from_slice(&endpoint.data_type);
+        payload.extend_from_slice(data);
+
+        let mut chunks = payload.chunks(REPORT_LENGTH + 1 - WRITE_HEADER);
+        self.command(&CMD_WRITE, chunks.next().unwrap())?;
+        for chunk in chunks {
+            self.command(&CMD_WRITE_MORE, chunk)?;
+        }
+        Ok(())
+    }
+
+    fn read_speeds(&mut self) -> Result<()> {
+        let data = self.read_endpoint(&SPEEDS)?;
+        let count = (*data.first().unwrap_or(&0) as usize).min(self.rpms.len());
+        for i in 0..count {
+            let rpm = le16(&data, 1 + i * 2)?;
+            if self.fans_connected[i] {
+                self.rpms[i] = Some(rpm);
+                self.stalls[i].sample(&self.fans[i], rpm);
+            } else {
+                self.rpms[i] = None;
+            }
+        }
+        Ok(())
+    }
+
+    fn read_temperatures(&mut self) -> Result<()> {
+        let data = self.read_endpoint(&TEMPERATURES)?;
+        let count = (*data.first().unwrap_or(&0) as usize).min(self.probes.len());
+        for i in 0..count {
+            let connected = data.get(1 + i * 3) == Some(&TEMP_CONNECTED);
+            let temp = le16(&data, 2 + i * 3)? as f32 / 10.0;
+            self.probes[i] = Some(temp).filter(|_| connected);
+        }
+        Ok(())
+    }
+
+    /// Work out the duty cycle for a fan setting. The firmware only takes a fixed duty while we
+    /// are in control, so rpm targets are approached a little every update.
+    fn duty(&self, index: usize) -> f32 {
+        let target = match &self.fans[index] {
+            &Fan::Pwm(duty) => return duty.clamp(0.0, 1.0),
+            &Fan::Rpm(rpm) => rpm as f32,
+            Fan::Curve(probe, curve) => match self.probes.get(*probe).cloned().flatten() {
+                Some(temp) => curve_rpm(curve, temp),
+                None => return 1.0,
+            },
+        };
+
+        match (self.rpms[index], target > 0.0) {
+            (_, false) => 0.0,
+            (Some(rpm), true) => {
+                let error = (target - rpm as f32) / target;
+                (self.duties[index] + error * RPM_CONTROL_GAIN).clamp(0.0, 1.0)
+            }
+            (None, true) => 1.0,
+        }
+    }
+
+    fn update_fans(&mut self) -> Result<()> {
+        for i in 0..self.fans.len() {
+            self.duties[i] = self.duty(i);
+        }
+        let percents: Vec<u8> = self
+            .duties
+            .iter()
+            .map(|duty| (duty * 100.0).round() as u8)
+            .collect();
+        if percents
+            .iter()
+            .zip(self.written.iter())
+            .all(|(p, w)| Some(*p) == *w)
+        {
+            return Ok(());
+        }
+
+        // every channel of the endpoint is written, so start from what the device has
+        let mut data = self.read_endpoint(&FIXED_PERCENT)?;
+        let channels = *data.first().unwrap_or(&0) as usize;
+        data.resize(1 + channels * 2, 0);
+        for (i, &percent) in percents.iter().enumerate().take(channels) {
+            data[1 + i * 2..3 + i * 2].copy_from_slice(&(percent as u16).to_le_bytes());
+        }
+        self.write_endpoint(&FIXED_PERCENT, &data)?;
+
+        for (written, percent) in self.written.iter_mut().zip(percents) {
+            *written = Some(percent);
+        }
+        Ok(())
+    }
+
+    fn update_strips(&mut self) -> Result<()> {
+        let mut data = Vec::new();
+        for (strip, &count) in self.strips.iter().zip(self.led_counts.iter()) {
+            // the firmware only runs its own lighting while asleep, so effects other than a
+            // plain color stay dark until the service exits
+            let fill = match strip.hardware.as_ref() {
+                Some(HardwareEffect::Static(color)) => Some(*color),
+                Some(_) => Some(Color::Rgb(0.0, 0.0, 0.0)),
+                None => None,
+            };
+            for i in 0..count {
+                let color = fill
+                    .or_else(|| strip.colors.get(i).cloned())
+                    .unwrap_or(Color::Rgb(0.0, 0.0, 0.0));
+                let [r, g, b] = color.rgb();
+                data.extend_from_slice(&[(r * 255.0) as u8, (g * 255.0) as u8, (b * 255.0) as u8]);
+            }
+        }
+
+        if !data.is_empty() {
+            self.write_endpoint(&COLORS, &data)?;
+        }
+        Ok(())
+    }
+}
+
+impl Device for CommanderCore {
+    fn initialize(&mut self) -> Result<()> {
+        self.command(&CMD_WAKE, &[])?;
+
+        let firmware = self.command(&CMD_GET_FIRMWARE, &[])?;
+        if firmware.len() < 3 {
+            bail!("Reply of {} bytes is too short", firmware.len());
+        }
+
+        let data = self.read_endpoint(&LED_COUNTS)?;
+        let ports = *data.first().unwrap_or(&0) as usize;
+        for i in 0..ports.min(self.led_counts.len()) {
+            self.led_counts[i] = match le16(&data, 1 + i * 4)? {
+                LED_PORT_CONNECTED => le16(&data, 3 + i * 4)? as usize,
+                _ => 0,
+            };
+        }
+        for (strip, &count) in self.strips.iter_mut().zip(self.led_counts.iter()) {
+            strip.leds = Some(count);
+        }
+
+        let data = self.read_endpoint(&CONNECTED_FANS)?;
+        let ports = *data.first().unwrap_or(&0) as usize;
+        for i in 0..ports.min(self.fans_connected.len()) {
+            self.fans_connected[i] = data.get(1 + i) == Some(&FAN_CONNECTED);
+        }
+
+        // take over every fan channel with a fixed duty
+        let mut modes = self.read_endpoint(&SPEED_MODES)?;
+        let channels = *modes.first().unwrap_or(&0) as usize;
+        modes.resize(1 + channels, 0);
+        for mode in modes[1..].iter_mut().take(self.fans.len()) {
+            *mode = SPEED_MODE_FIXED_PERCENT;
+        }
+        self.write_endpoint(&SPEED_MODES, &modes)?;
+
+        self.read_temperatures()?;
+        self.read_speeds()?;
+
+        log::info!(
+            "{}: \n FW version {}.{}.{} \n Leds: {:?} \n Fans: {:?} \n Temperature: {:?}",
+            self.name,
+            firmware[0],
+            firmware[1],
+            firmware[2],
+            self.led_counts,
+            self.fans_connected,
+            self.probes
+        );
+
+        self.written = vec![None; self.fans.len()];
+        self.strips_dirty = true;
+
+        Ok(())
+    }
+
+    fn is_led_only(&self) -> bool {
+        false
+    }
+
+    fn name(&self) -> &str {
+        self.name.as_str()
+    }
+
+    fn fans(&mut self) -> &mut [Fan] {
+        &mut self.fans
+    }
+
+    fn strips(&mut self) -> &mut [Strip] {
+        self.strips_dirty = true;
+        &mut self.strips
+    }
+
+    fn probes(&self) -> &[Option<f32>] {
+        &self.probes
+    }
+
+    fn rpms(&self) -> Vec<Option<u16>> {
+        self.rpms.clone()
+    }
+
+    fn stalled_fans(&self, samples: usize, min_duty: f32) -> Vec<usize> {
+        stalled(&self.stalls, samples, min_duty)
+    }
+
+    fn report_status(&self) {
+        log::info!(
+            target: format!("{} status", self.name).as_str(),
+            "temperatures = {:?}, fan speeds = {:?}, duties = {:?}",
+            self.probes,
+            self.rpms,
+            self.written
+        )
+    }
+
+    fn update(&mut self) -> Result<()> {
+        if self.sample_temps {
+            self.read_temperatures()?;
+        } else {
+            self.read_speeds()?;
+        }
+        self.sample_temps = !self.sample_temps;
+
+        self.update_fans()?;
+
+        if self.strips_dirty {
+            self.update_strips()?;
+            self.strips_dirty = false;
+        }
+
+        Ok(())
+    }
+
+    fn shutdown(&mut self, shutdown: &Shutdown) -> Result<()> {
+        // the fixed duty holds until the sleep command hands the fans back to the firmware,
+        // which runs them on its own curve from then on
+        for fan in self.fans.iter_mut() {
+            *fan = Fan::Pwm(shutdown.fan_duty);
+        }
+        self.update_fans()?;
+
+        self.command(&CMD_SLEEP, &[])?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+    use std::rc::Rc;
+
+    use super::*;
+
+    /// Keeps the endpoints of a Commander CORE with the pump and two fans connected.
+    #[derive(Default)]
+    struct FakeCore {
+        endpoints: RefCell<HashMap<Vec<u8>, Vec<u8>>>,
+        open: RefCell<Vec<u8>>,
+        writing: RefCell<Vec<u8>>,
+        reply: RefCell<Option<Vec<u8>>>,
+    }
+
+    impl FakeCore {
+        fn new() -> Rc<Self> {
+            let core = Self::default();
+            let mut speeds = vec![0x06, 0x00, 7];
+            for rpm in [2000u16, 900, 0, 0, 0, 0, 0].iter() {
+                speeds.extend_from_slice(&rpm.to_le_bytes());
+            }
+            core.set(&SPEEDS, &speeds[2..]);
+            core.set(&CONNECTED_FANS, &[7, 7, 7, 7, 1, 1, 1, 1]);
+            core.set(
+                &LED_COUNTS,
+                &[
+                    7, 2, 0, 24, 0, 2, 0, 8, 0, 3, 0, 0, 0, 3, 0, 0, 0, 3, 0, 0, 0, 3, 0, 0, 0, 3,
+                    0, 0, 0,
+                ],
+            );
+            core.set(&TEMPERATURES, &[1, 0x00, 0x5e, 0x01]);
+            core.set(&SPEED_MODES, &[7, 2, 2, 2, 2, 2, 2, 2]);
+            core.set(
+                &FIXED_PERCENT,
+                &[7, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+            );
+            Rc::new(core)
+        }
+
+        fn set(&self, endpoint: &Endpoint, data: &[u8]) {
+            let mut content = endpoint.data_type.to_vec();
+            content.extend_from_slice(data);
+            self.endpoints
+                .borrow_mut()
+                .insert(endpoint.address.to_vec(), content);
+        }
+
+        /// The data of an endpoint, without its data type.
+        fn get(&self, endpoint: &Endpoint) -> Vec<u8> {
+            self.endpoints.borrow()[endpoint.address][2..].to_vec()
+        }
+
+        /// Keeps what was written to the open endpoint, once all of it arrived.
+        fn store(&self) {
+            let writing = self.writing.borrow();
+            let length = u16::from_le_bytes([writing[0], writing[1]]) as usize;
+            if writing.len() >= 4 + length {
+                let open = self.open.borrow().clone();
+                self.endpoints
+                    .borrow_mut()
+                    .insert(open, writing[4..4 + length].to_vec());
+            }
+        }
+    }
+
+    impl Transport for FakeCore {
+        fn write(&self, data: &[u8]) -> Result<usize> {
+            assert_eq!(data.len(), REPORT_LENGTH + 1);
+            assert_eq!(data[..2], [0x00, 0x08]);
+            let command = &data[2..];
+
+            let reply = match command[..2] {
+                [0x01, 0x03] | [0x05, 0x01] => vec![],
+                [0x02, 0x13] => vec![2, 4, 1],
+                [0x0d, 0x00] => {
+                    let end = command.iter().rposition(|&byte| byte != 0).unwrap() + 1;
+                    *self.open.borrow_mut() = command[2..end].to_vec();
+                    vec![]
+                }
+                [0x08, 0x00] => self.endpoints.borrow()[&*self.open.borrow()].clone(),
+                [0x06, 0x00] => {
+                    *self.writing.borrow_mut() = command[2..].to_vec();
+                    self.store();
+                    vec![]
+                }
+                [0x07, 0x00] => {
+                    self.writing.borrow_mut().extend_from_slice(&command[2..]);
+                    self.store();
+                    vec![]
+                }
+                _ => panic!("Unexpected command {:02x?}", &command[..4]),
+            };
+
+            let mut report = vec![0x00, command[0], 0x00];
+            report.extend(reply);
+            *self.reply.borrow_mut() = Some(report);
+            Ok(data.len())
+        }
+
+        fn read_timeout(&self, buf: &mut [u8], _timeout: i32) -> Result<usize> {
+            match self.reply.borrow_mut().take() {
+                Some(reply) => {
+                    buf[..reply.len()].copy_from_slice(&reply);
+                    Ok(reply.len())
+                }
+                None => Ok(0),
+            }
+        }
+    }
+
+    fn commander_core() -> (CommanderCore, Rc<FakeCore>) {
+        let fake = FakeCore::new();
+        let mut device = CommanderCore::new_commander_core(fake.clone());
+        device.initialize().unwrap();
+        (device, fake)
+    }
+
+    #[test]
+    fn initialize_takes_over_the_fans() {
+        let (device, fake) = commander_core();
+        assert_eq!(fake.get(&SPEED_MODES), vec![7, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(device.led_counts, vec![24, 8, 0, 0, 0, 0, 0]);
+        assert_eq!(device.strips[1].leds, Some(8));
+        assert_eq!(device.probes(), &[Some(35.0)]);
+        assert_eq!(
+            device.rpms(),
+            vec![Some(2000), Some(900), Some(0), None, None, None, None]
+        );
+    }
+
+    #[test]
+    fn only_connected_fans_stall() {
+        let (mut device, _fake) = commander_core();
+        for _ in 0..10 {
+            device.update().unwrap();
+        }
+        assert_eq!(device.stalled_fans(5, 0.2), vec![2]);
+    }
+
+    #[test]
+    fn update_writes_fixed_percents() {
+        let (mut device, fake) = commander_core();
+        device.fans()[0] = Fan::Pwm(1.0);
+        device.fans()[1] = Fan::Pwm(0.5);
+        device.update().unwrap();
+        assert_eq!(
+            fake.get(&FIXED_PERCENT),
+            vec![7, 100, 0, 50, 0, 25, 0, 25, 0, 25, 0, 25, 0, 25, 0]
+        );
+    }
+
+    #[test]
+    fn update_writes_colors() {
+        let (mut device, fake) = commander_core();
+        device.strips()[0].colors = vec![Color::Rgb(1.0, 0.0, 0.0); 30];
+        device.strips()[1].hardware = Some(HardwareEffect::Static(Color::Rgb(0.0, 0.0, 1.0)));
+        device.update().unwrap();
+
+        // the colors take more than one report
+        let mut colors = [255, 0, 0].repeat(24);
+        colors.extend([0, 0, 255].repeat(8));
+        assert_eq!(fake.get(&COLORS), colors);
+    }
+}
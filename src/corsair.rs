@@ -4,23 +4,34 @@ use std::cell::Cell;
 use std::time::Instant;
 
 use anyhow::*;
-use hidapi::{HidDevice, HidResult};
 
 use crate::color::Color;
-use crate::device::{Device, Fan, Strip};
+use crate::corsair_protocol::*;
+use crate::device::{
+    stalled, Device, Fan, HardwareEffect, LedDirection, LedSpeed, Shutdown, StallCounter, Strip,
+};
+use crate::driver::{Driver, Registry};
+use crate::transport::Transport;
 use std::ops::AddAssign;
 
 pub struct CorsairLighting {
     name: String,
-    device: HidDevice,
+    device: Box<dyn Transport>,
     fans: Vec<Fan>,
     fans_dirty: bool,
     strips: Vec<Strip>,
     strips_dirty: bool,
+    /// The firmware effect running on each channel, or `None` for channels driven by us.
+    hardware_effects: Vec<Option<HardwareEffect>>,
+    /// Temperature probes, followed by the voltage rails.
     probes: Vec<Option<f32>>,
+    temperatures: usize,
+    probes_connected: Vec<bool>,
     fan_modes: Vec<FanMode>,
-    rpms: Vec<u16>,
+    rpms: Vec<Option<u16>>,
+    stalls: Vec<StallCounter>,
     next_sample: usize,
+    /// Commands that were sent without waiting for their reply.
     backlog: Cell<usize>,
 }
 
@@ -31,147 +42,289 @@ enum FanMode {
     Pwm,
 }
 
-const REPORT_LENGTH: usize = 64;
-const RESPONSE_LENGTH: usize = 16;
-
-const CMD_GET_FIRMWARE: u8 = 0x02;
-const CMD_GET_BOOTLOADER: u8 = 0x06;
-const CMD_GET_TEMP_CONFIG: u8 = 0x10;
-const CMD_GET_TEMP: u8 = 0x11;
-const CMD_GET_VOLTS: u8 = 0x12;
-const CMD_GET_FAN_MODES: u8 = 0x20;
-const CMD_GET_FAN_RPM: u8 = 0x21;
-const CMD_SET_FAN_DUTY: u8 = 0x23;
-const CMD_SET_FAN_PROFILE: u8 = 0x25;
-
-const CMD_RESET_LED_CHANNEL: u8 = 0x37;
-const CMD_BEGIN_LED_EFFECT: u8 = 0x34;
-const CMD_SET_LED_CHANNEL_STATE: u8 = 0x38;
-const CMD_LED_EFFECT: u8 = 0x35;
-const CMD_LED_COMMIT: u8 = 0x33;
-const CMD_LED_DIRECT: u8 = 0x32;
-
-const LED_PORT_STATE_HARDWARE: u8 = 0x01;
-const LED_PORT_STATE_SOFTWARE: u8 = 0x02;
-const LED_SPEED_FAST: u8 = 0x00;
-const LED_SPEED_MEDIUM: u8 = 0x01;
-const LED_SPEED_SLOW: u8 = 0x02;
-
-const LED_DIRECTION_FORWARD: u8 = 0x01;
-const LED_DIRECTION_BACKWARD: u8 = 0x00;
-
-const FAN_MODE_DISCONNECTED: u8 = 0x00;
-const FAN_MODE_DC: u8 = 0x01;
-const FAN_MODE_PWM: u8 = 0x02;
+const RAILS: [&str; 3] = ["12v", "5v", "3.3v"];
+
+/// How long to wait for a reply, in milliseconds.
+const READ_TIMEOUT: i32 = 100;
+const REQUEST_ATTEMPTS: usize = 3;
+/// What a connected probe that fails to read counts as, so the fans speed up rather than stay
+/// slow. A sensor filter with a `range` below this treats it as a failed read instead.
+const FAILED_PROBE_TEMP: f32 = 100.0;
+
+pub fn register(registry: &mut Registry) {
+    registry.register(Driver {
+        name: "Commander PRO",
+        vendor_id: 0x1b1c,
+        product_id: 0x0c10,
+        interface: None,
+        open: |device| Box::new(CorsairLighting::new_commander_pro(device)),
+    });
+    registry.register(Driver {
+        name: "Lighting Node PRO",
+        vendor_id: 0x1b1c,
+        product_id: 0x0c0b,
+        interface: None,
+        open: |device| Box::new(CorsairLighting::new_lighting_node_pro(device)),
+    });
+    registry.register(Driver {
+        name: "Lighting Node CORE",
+        vendor_id: 0x1b1c,
+        product_id: 0x0c1a,
+        interface: None,
+        open: |device| Box::new(CorsairLighting::new_lighting_node_core(device)),
+    });
+}
+
+fn pair(colors: &Option<(Color, Color)>) -> Vec<Color> {
+    colors.map_or(vec![], |(a, b)| vec![a, b])
+}
 
 impl CorsairLighting {
-    pub fn new_commander_pro(device: HidDevice) -> Self {
+    pub fn new_commander_pro(device: impl Transport + 'static) -> Self {
         Self {
             name: String::from("Commander PRO"),
-            device,
+            device: Box::new(device),
             fans: vec![Fan::Pwm(0.25); 6],
             fans_dirty: true,
-            strips: vec![Strip { colors: Vec::new() }, Strip { colors: Vec::new() }],
+            strips: vec![Strip::default(); 2],
             strips_dirty: true,
-            probes: vec![None; 4],
+            hardware_effects: vec![None; 2],
+            probes: vec![None; 4 + RAILS.len()],
+            temperatures: 4,
+            probes_connected: vec![false; 4],
             fan_modes: vec![FanMode::Off; 6],
-            rpms: vec![0; 6],
+            rpms: vec![None; 6],
+            stalls: vec![StallCounter::default(); 6],
             next_sample: 0,
             backlog: Cell::new(0),
         }
     }
 
-    pub fn new_lighting_node_core(device: HidDevice) -> Self {
+    pub fn new_lighting_node_pro(device: impl Transport + 'static) -> Self {
         Self {
-            name: String::from("Lighting Node CORE"),
-            device,
+            name: String::from("Lighting Node PRO"),
+            device: Box::new(device),
             fans: vec![],
             fans_dirty: true,
-            strips: vec![Strip { colors: Vec::new() }],
+            strips: vec![Strip::default(); 2],
             strips_dirty: true,
+            hardware_effects: vec![None; 2],
             probes: vec![],
+            temperatures: 0,
+            probes_connected: vec![],
             fan_modes: vec![],
             rpms: vec![],
+            stalls: vec![],
             next_sample: 0,
             backlog: Cell::new(0),
         }
     }
 
-    fn get_temp(&self, index: usize) -> HidResult<f32> {
-        let res = self.request(CMD_GET_TEMP, &[index as u8])?;
-        Ok(res[1] as f32 * 2.56 + res[2] as f32 * 0.01)
+    pub fn new_lighting_node_core(device: impl Transport + 'static) -> Self {
+        Self {
+            name: String::from("Lighting Node CORE"),
+            device: Box::new(device),
+            fans: vec![],
+            fans_dirty: true,
+            strips: vec![Strip::default()],
+            strips_dirty: true,
+            hardware_effects: vec![None],
+            probes: vec![],
+            temperatures: 0,
+            probes_connected: vec![],
+            fan_modes: vec![],
+            rpms: vec![],
+            stalls: vec![],
+            next_sample: 0,
+            backlog: Cell::new(0),
+        }
     }
 
-    fn get_rpm(&self, index: usize) -> HidResult<u16> {
-        let res = self.request(CMD_GET_FAN_RPM, &[index as u8])?;
-        Ok(res[1] as u16 * 256 + res[2] as u16)
+    fn send(&self, command: Command) -> Result<()> {
+        self.device.write(&command.encode())?;
+        self.backlog.set(self.backlog.get() + 1);
+        Ok(())
     }
 
-    fn send(&self, command: u8, data: &[u8]) -> HidResult<()> {
-        let mut buf = [0u8; REPORT_LENGTH];
-        buf[1] = command;
-        buf[2..2 + data.len()].copy_from_slice(data);
-        self.device.write(&buf)?;
-        Ok(())
+    fn request<R: Response>(&self, command: Command) -> Result<R> {
+        self.try_request(&command)?
+            .ok_or_else(|| anyhow!("{} gave no valid reply to {:?}", self.name, command))
     }
 
-    fn request(&self, command: u8, data: &[u8]) -> HidResult<[u8; RESPONSE_LENGTH]> {
-        let mut buf = [0u8; REPORT_LENGTH];
-        buf[1] = command;
-        buf[2..2 + data.len()].copy_from_slice(data);
-        self.device.write(&buf)?;
+    /// Send a request, retrying when the reply can't be decoded. Failing to talk to the device
+    /// at all is an error, while a reply that is still invalid after retrying gives `None`.
+    fn try_request<R: Response>(&self, command: &Command) -> Result<Option<R>> {
+        let report = command.encode();
+        let mut attempt = 1;
+        loop {
+            self.flush()?;
+            self.device.write(&report)?;
+
+            let mut reply = [0u8; RESPONSE_LENGTH];
+            let len = self.device.read_timeout(&mut reply, READ_TIMEOUT)?;
+            match command.decode(&reply[..len]) {
+                Ok(response) => return Ok(Some(response)),
+                Err(e) if attempt < REQUEST_ATTEMPTS => {
+                    log::warn!("{}: {:?}, retrying", self.name, e);
+                    attempt += 1;
+                }
+                Err(e) => {
+                    log::warn!(
+                        "{}: {:?}, giving up after {} attempts",
+                        self.name,
+                        e,
+                        attempt
+                    );
+                    return Ok(None);
+                }
+            }
+        }
+    }
 
-        let mut response = [0u8; RESPONSE_LENGTH];
-        if self.device.read(&mut response)? > 0 {
-            Ok(response)
+    /// Read a temperature probe or voltage rail. A reply that is still invalid after retrying
+    /// leaves the sensor without a reading.
+    fn sample_probe(&self, index: usize) -> Result<Option<f32>> {
+        let reading = if index < self.temperatures {
+            // a failed probe reads zero
+            self.try_request(&Command::GetTemp(index as u8))?
+                .map(|Temperature(temp)| temp)
+                .filter(|&temp| temp > 0.0)
+                .or(Some(FAILED_PROBE_TEMP))
         } else {
-            Ok(response)
+            let rail = (index - self.temperatures) as u8;
+            self.try_request(&Command::GetVolts(rail))?
+                .map(|Voltage(volts)| volts)
+        };
+        Ok(reading)
+    }
+
+    /// Collect the replies to commands that were sent earlier, so that they can't be mistaken
+    /// for the reply to the next request.
+    fn flush(&self) -> Result<()> {
+        let mut reply = [0u8; RESPONSE_LENGTH];
+        while self.backlog.get() > 0 {
+            let len = self.device.read_timeout(&mut reply, READ_TIMEOUT)?;
+            if len == 0 {
+                log::warn!("{}: {} replies went missing", self.name, self.backlog.get());
+                break;
+            }
+            if let Err(e) = decode::<Ack>(&reply[..len]) {
+                log::warn!("{}: an earlier command failed: {}", self.name, e);
+            }
+            self.backlog.set(self.backlog.get() - 1);
         }
+        self.backlog.set(0);
+
+        // anything left is a late reply to a request that was given up on
+        while self.device.read_timeout(&mut reply, 0)? > 0 {}
+        Ok(())
+    }
+
+    /// Hand a channel over to the firmware, running `effect`.
+    fn set_hardware_effect(&self, channel: u8, effect: &HardwareEffect) -> Result<()> {
+        let (mode, speed, direction, colors): (u8, _, _, Vec<Color>) = match effect {
+            HardwareEffect::RainbowWave { speed, direction } => (0x00, *speed, *direction, vec![]),
+            HardwareEffect::ColorShift { speed, colors } => {
+                (0x01, *speed, LedDirection::Forward, pair(colors))
+            }
+            HardwareEffect::ColorPulse { speed, colors } => {
+                (0x02, *speed, LedDirection::Forward, pair(colors))
+            }
+            HardwareEffect::ColorWave {
+                speed,
+                direction,
+                colors,
+            } => (0x03, *speed, *direction, pair(colors)),
+            HardwareEffect::Static(color) => {
+                (0x04, LedSpeed::Medium, LedDirection::Forward, vec![*color])
+            }
+            HardwareEffect::Temperature(points) => (
+                0x05,
+                LedSpeed::Medium,
+                LedDirection::Forward,
+                points.iter().map(|&(_, color)| color).collect(),
+            ),
+            HardwareEffect::Visor {
+                speed,
+                direction,
+                colors,
+            } => (0x06, *speed, *direction, pair(colors)),
+            HardwareEffect::Marquee {
+                speed,
+                direction,
+                color,
+            } => (0x07, *speed, *direction, vec![*color]),
+            HardwareEffect::Blink { speed, colors } => {
+                (0x08, *speed, LedDirection::Forward, pair(colors))
+            }
+            HardwareEffect::Sequential {
+                speed,
+                direction,
+                color,
+            } => (0x09, *speed, *direction, vec![*color]),
+            HardwareEffect::Rainbow { speed } => (0x0a, *speed, LedDirection::Forward, vec![]),
+        };
+
+        let effect = LedEffect {
+            channel,
+            led_count: 204,
+            mode,
+            speed: match speed {
+                LedSpeed::Fast => LED_SPEED_FAST,
+                LedSpeed::Medium => LED_SPEED_MEDIUM,
+                LedSpeed::Slow => LED_SPEED_SLOW,
+            },
+            direction: match direction {
+                LedDirection::Forward => LED_DIRECTION_FORWARD,
+                LedDirection::Backward => LED_DIRECTION_BACKWARD,
+            },
+            colors: colors
+                .iter()
+                .map(|color| {
+                    let [r, g, b] = color.rgb();
+                    [(r * 255.0) as u8, (g * 255.0) as u8, (b * 255.0) as u8]
+                })
+                .collect(),
+            temperatures: match effect {
+                HardwareEffect::Temperature(points) => [points[0].0, points[1].0, points[2].0],
+                _ => [0.0; 3],
+            },
+        };
+
+        self.send(Command::ResetLedChannel(channel))?;
+        self.send(Command::BeginLedEffect(channel))?;
+        self.send(Command::SetLedChannelState {
+            channel,
+            hardware: true,
+        })?;
+        self.send(Command::LedEffect(effect))?;
+        self.send(Command::LedCommit(channel))
     }
 
-    fn update_fans(&self) -> HidResult<()> {
+    fn update_fans(&self) -> Result<()> {
         for (i, fan) in self.fans.iter().enumerate() {
+            let channel = i as u8;
             match fan {
-                Fan::Pwm(duty) => {
-                    self.send(
-                        CMD_SET_FAN_DUTY,
-                        &[i as u8, (duty * 100.0).min(100.0).max(0.0) as u8],
-                    )?;
-                }
-                Fan::Rpm(rpm) => {
-                    let rpm_lo = rpm & 0xff;
-                    let rpm_hi = (rpm & 0xff00) >> 8;
-
-                    let mut buf = [0; 25];
-                    buf[0] = i as u8;
-                    buf[1] = 0; // sensor 1, doesn't matter much
-                    for j in 0..6 {
-                        buf[2 + j * 2] = 0;
-                        buf[3 + j * 2] = 0;
-                        buf[14 + j * 2] = rpm_hi as u8;
-                        buf[15 + j * 2] = rpm_lo as u8;
-                    }
-
-                    self.send(CMD_SET_FAN_PROFILE, &buf)?;
-                }
-                Fan::Curve(sensor, curve) => {
-                    let mut buf = [0; 32];
-                    buf[0] = i as u8;
-                    buf[1] = *sensor as u8;
-                    for j in 0..6 {
-                        let rpm_lo = curve[j].rpm & 0xff;
-                        let rpm_hi = (curve[j].rpm & 0xff00) >> 8;
-
-                        let temp_lo = (curve[j].temp * 100.0) as u16 & 0xff;
-                        let temp_hi = ((curve[j].temp * 100.0) as u16 & 0xff00) >> 8;
-
-                        buf[2 + j * 2] = temp_hi as u8;
-                        buf[3 + j * 2] = temp_lo as u8;
-                        buf[14 + j * 2] = rpm_hi as u8;
-                        buf[15 + j * 2] = rpm_lo as u8;
+                Fan::Pwm(duty) => self.send(Command::SetFanDuty {
+                    channel,
+                    percent: (duty * 100.0).clamp(0.0, 100.0) as u8,
+                })?,
+                // a flat curve holds the fan at a fixed speed, the probe doesn't matter much
+                &Fan::Rpm(rpm) => self.send(Command::SetFanProfile {
+                    channel,
+                    probe: 0,
+                    points: [(0.0, rpm); 6],
+                })?,
+                Fan::Curve(probe, curve) => {
+                    let mut points = [(0.0, 0); 6];
+                    for (point, config) in points.iter_mut().zip(curve.iter()) {
+                        *point = (config.temp, config.rpm);
                     }
-
-                    self.send(CMD_SET_FAN_PROFILE, &buf)?;
+                    self.send(Command::SetFanProfile {
+                        channel,
+                        probe: *probe as u8,
+                        points,
+                    })?;
                 }
             }
         }
@@ -179,38 +332,49 @@ impl CorsairLighting {
         Ok(())
     }
 
-    fn update_strips(&self) -> HidResult<()> {
-        for (channel, strip) in self.strips.iter().enumerate() {
-            let channel = channel as u8;
+    fn update_strips(&mut self) -> Result<()> {
+        for channel in 0..self.strips.len() {
+            let strip = &self.strips[channel];
+
+            if let Some(effect) = strip.hardware.as_ref() {
+                if self.hardware_effects[channel].as_ref() != Some(effect) {
+                    self.set_hardware_effect(channel as u8, effect)?;
+                    self.hardware_effects[channel] = Some(effect.clone());
+                }
+                continue;
+            }
 
             if strip.colors.is_empty() {
                 continue;
             }
 
-            self.send(
-                CMD_SET_LED_CHANNEL_STATE,
-                &[channel, LED_PORT_STATE_SOFTWARE],
-            )?;
+            self.hardware_effects[channel] = None;
+            let channel = channel as u8;
+
+            self.send(Command::SetLedChannelState {
+                channel,
+                hardware: false,
+            })?;
 
-            let mut start_led = 0;
+            let mut start = 0;
             for chunk in strip.colors.chunks(50) {
-                let mut buf = [0; 54];
-                buf[0] = channel;
-                buf[1] = start_led;
-                buf[2] = chunk.len() as u8;
-
-                for i in 0..3 {
-                    buf[3] = i as u8;
-                    for j in 0..chunk.len() {
-                        buf[4 + j] = (chunk[j].rgb()[i] * 255.0) as u8;
-                    }
-                    self.send(CMD_LED_DIRECT, &buf)?;
+                for component in 0..3 {
+                    let values: Vec<u8> = chunk
+                        .iter()
+                        .map(|color| (color.rgb()[component] * 255.0) as u8)
+                        .collect();
+                    self.send(Command::LedDirect {
+                        channel,
+                        start,
+                        component: component as u8,
+                        values: &values,
+                    })?;
                 }
 
-                start_led += chunk.len() as u8;
+                start += chunk.len() as u8;
             }
 
-            self.send(CMD_LED_COMMIT, &[channel])?;
+            self.send(Command::LedCommit(channel))?;
         }
 
         Ok(())
@@ -219,23 +383,27 @@ impl CorsairLighting {
 
 impl Device for CorsairLighting {
     fn initialize(&mut self) -> Result<()> {
-        let [ma, mi, p, ..] = self.request(CMD_GET_FIRMWARE, &[])?;
-        let [bma, bmi, ..] = self.request(CMD_GET_BOOTLOADER, &[])?;
-
-        if !self.probes.is_empty() {
-            let probes_config = self.request(CMD_GET_TEMP_CONFIG, &[])?;
-            for i in 0..self.probes.len() {
-                if probes_config[i + 1] > 0 {
-                    let temp = self.get_temp(i)?;
-                    self.probes[i].replace(temp);
+        let FirmwareVersion(ma, mi, p) = self.request(Command::GetFirmware)?;
+        let BootloaderVersion(bma, bmi) = self.request(Command::GetBootloader)?;
+
+        if self.temperatures > 0 {
+            let TempConfig(connected) = self.request(Command::GetTempConfig)?;
+            for (i, &connected) in connected.iter().enumerate().take(self.temperatures) {
+                if connected {
+                    self.probes[i] = self.sample_probe(i)?;
+                    self.probes_connected[i] = true;
                 }
             }
         }
 
+        for i in self.temperatures..self.probes.len() {
+            self.probes[i] = self.sample_probe(i)?;
+        }
+
         if !self.fans.is_empty() {
-            let fan_modes = self.request(CMD_GET_FAN_MODES, &[])?;
-            for i in 0..self.fans.len() {
-                self.fan_modes[i] = match fan_modes[i + 1] {
+            let FanModes(fan_modes) = self.request(Command::GetFanModes)?;
+            for (mode, &byte) in self.fan_modes.iter_mut().zip(fan_modes.iter()) {
+                *mode = match byte {
                     FAN_MODE_DISCONNECTED => FanMode::Off,
                     FAN_MODE_DC => FanMode::Dc,
                     FAN_MODE_PWM => FanMode::Pwm,
@@ -244,25 +412,8 @@ impl Device for CorsairLighting {
             }
         }
 
-        for i in 0..self.strips.len() as u8 {
-            self.send(CMD_RESET_LED_CHANNEL, &[i])?;
-            self.send(CMD_BEGIN_LED_EFFECT, &[i])?;
-            self.send(CMD_SET_LED_CHANNEL_STATE, &[i, LED_PORT_STATE_HARDWARE])?;
-            self.send(
-                CMD_LED_EFFECT,
-                &[
-                    i,
-                    0,
-                    204,
-                    0x06,
-                    LED_SPEED_MEDIUM,
-                    LED_DIRECTION_FORWARD,
-                    0x01,
-                    0xff,
-                ],
-            )?;
-            self.send(CMD_LED_COMMIT, &[i])?;
-        }
+        // channels are handed to the firmware or back again by the next update
+        self.hardware_effects = vec![None; self.strips.len()];
 
         log::info!("{}: \n FW version {}.{}.{} \n Bootloader version {}.{} \n Temperature: {:?} \n Fan modes: {:?}", self.name, ma, mi, p, bma, bmi, self.probes, self.fan_modes);
 
@@ -294,11 +445,39 @@ impl Device for CorsairLighting {
         &self.probes
     }
 
+    fn probe_names(&self) -> Vec<String> {
+        (1..=self.temperatures)
+            .map(|i| format!("temp{}", i))
+            .chain(RAILS.iter().map(|rail| rail.to_string()))
+            .take(self.probes.len())
+            .collect()
+    }
+
+    fn curve_probes(&self) -> usize {
+        self.temperatures
+    }
+
+    fn rpms(&self) -> Vec<Option<u16>> {
+        self.rpms
+            .iter()
+            .zip(self.fan_modes.iter())
+            .map(|(&rpm, mode)| match mode {
+                FanMode::Off => None,
+                _ => rpm,
+            })
+            .collect()
+    }
+
+    fn stalled_fans(&self, samples: usize, min_duty: f32) -> Vec<usize> {
+        stalled(&self.stalls, samples, min_duty)
+    }
+
     fn report_status(&self) {
         log::info!(
             target: format!("{} status", self.name).as_str(),
-            "temperatures = {:?}, fan speeds = {:?}",
-            self.probes,
+            "temperatures = {:?}, voltages = {:?}, fan speeds = {:?}",
+            &self.probes[..self.temperatures],
+            &self.probes[self.temperatures..],
             self.rpms
         )
     }
@@ -306,28 +485,34 @@ impl Device for CorsairLighting {
     fn update(&mut self) -> Result<()> {
         let mut current_sample = 0;
 
-        // flush the input stream
-        self.device.set_blocking_mode(false);
-        while self.device.read(&mut [0; RESPONSE_LENGTH])? > 0 {}
-        self.device.set_blocking_mode(true);
-
-        for i in 0..self.probes.len() {
-            if self.probes[i].is_some() {
+        for i in 0..self.temperatures {
+            if self.probes_connected[i] {
                 if current_sample == self.next_sample {
-                    let temp = self.get_temp(i)?;
-                    if temp > 0.0 {
-                        self.probes[i].replace(temp);
-                    } else {
-                        self.probes[i].replace(100.0);
-                    }
+                    self.probes[i] = self.sample_probe(i)?;
                 }
                 current_sample += 1;
             }
         }
 
+        for i in self.temperatures..self.probes.len() {
+            if current_sample == self.next_sample {
+                self.probes[i] = self.sample_probe(i)?;
+            }
+            current_sample += 1;
+        }
+
         for i in 0..self.fans.len() {
             if current_sample == self.next_sample {
-                self.rpms[i] = self.get_rpm(i)?;
+                match self.try_request(&Command::GetFanRpm(i as u8))? {
+                    Some(Rpm(rpm)) => {
+                        self.rpms[i] = Some(rpm);
+                        match self.fan_modes[i] {
+                            FanMode::Off => {}
+                            _ => self.stalls[i].sample(&self.fans[i], rpm),
+                        }
+                    }
+                    None => self.rpms[i] = None,
+                }
             }
             current_sample += 1;
         }
@@ -348,4 +533,267 @@ impl Device for CorsairLighting {
 
         Ok(())
     }
+
+    fn shutdown(&mut self, shutdown: &Shutdown) -> Result<()> {
+        let probe = self
+            .probes_connected
+            .iter()
+            .position(|&connected| connected);
+        for fan in self.fans.iter_mut() {
+            *fan = match probe {
+                Some(probe) => Fan::Curve(probe, shutdown.fan_curve.clone()),
+                None => Fan::Pwm(shutdown.fan_duty),
+            };
+        }
+        self.update_fans()?;
+
+        for i in 0..self.strips.len() {
+            self.set_hardware_effect(i as u8, shutdown.effect(i))?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::device::TempRpm;
+    use crate::transport::MockTransport;
+
+    /// A Commander PRO with probe 1 and fans 1 and 2 connected.
+    fn commander_pro() -> (CorsairLighting, Rc<MockTransport>) {
+        let transport = Rc::new(MockTransport::new());
+        // everything else is acknowledged
+        transport.respond(&[0], &[0]);
+        transport.respond(&[0, 0x02], &[0, 0, 9, 129]);
+        transport.respond(&[0, 0x06], &[0, 0, 5]);
+        transport.respond(&[0, 0x10], &[0, 1, 0, 0, 0]);
+        transport.respond(&[0, 0x11, 0], &[0, 0x0b, 0xb8]);
+        transport.respond(&[0, 0x12], &[0, 0x2e, 0xe0]);
+        transport.respond(&[0, 0x20], &[0, 2, 1, 0, 0, 0, 0]);
+        transport.respond(&[0, 0x21], &[0, 0x04, 0xb0]);
+        let device = CorsairLighting::new_commander_pro(transport.clone());
+        (device, transport)
+    }
+
+    /// The written reports, without the report id and trailing zeros.
+    fn reports(transport: &MockTransport) -> Vec<Vec<u8>> {
+        transport
+            .take_written()
+            .into_iter()
+            .map(|report| {
+                assert_eq!(report.len(), REPORT_LENGTH);
+                assert_eq!(report[0], 0);
+                let end = report.iter().rposition(|&byte| byte != 0).unwrap_or(0);
+                report[1..=end.max(1)].to_vec()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn initialize_reads_probes_and_fan_modes() {
+        let (mut device, transport) = commander_pro();
+        device.initialize().unwrap();
+
+        assert_eq!(
+            reports(&transport),
+            vec![
+                vec![0x02],
+                vec![0x06],
+                vec![0x10],
+                vec![0x11],
+                vec![0x12],
+                vec![0x12, 1],
+                vec![0x12, 2],
+                vec![0x20],
+            ]
+        );
+        assert_eq!(
+            device.probes(),
+            &[
+                Some(30.0),
+                None,
+                None,
+                None,
+                Some(12.0),
+                Some(12.0),
+                Some(12.0)
+            ]
+        );
+        assert_eq!(
+            device.rpms(),
+            vec![None, None, None, None, None, None],
+            "nothing was sampled yet"
+        );
+    }
+
+    #[test]
+    fn lighting_node_pro_has_only_strips() {
+        let transport = Rc::new(MockTransport::new());
+        transport.respond(&[0], &[0]);
+        transport.respond(&[0, 0x02], &[0, 0, 9, 129]);
+        transport.respond(&[0, 0x06], &[0, 0, 5]);
+        let mut device = CorsairLighting::new_lighting_node_pro(transport.clone());
+        device.initialize().unwrap();
+
+        assert_eq!(device.name(), "Lighting Node PRO");
+        assert!(device.is_led_only());
+        assert!(device.fans().is_empty());
+        assert!(device.probes().is_empty());
+        assert!(device.rpms().is_empty());
+        assert_eq!(device.strips().len(), 2);
+        // no probes or fans are asked for
+        assert_eq!(reports(&transport), vec![vec![0x02], vec![0x06]]);
+    }
+
+    #[test]
+    fn failed_probe_reads_hot() {
+        let (mut device, transport) = commander_pro();
+        transport.respond(&[0, 0x11, 0], &[0, 0, 0]);
+        device.initialize().unwrap();
+        assert_eq!(device.probes()[0], Some(FAILED_PROBE_TEMP));
+        assert_eq!(device.probes()[1], None, "unplugged probes aren't read");
+    }
+
+    #[test]
+    fn update_fans_layout() {
+        let (mut device, transport) = commander_pro();
+        let curve = [
+            TempRpm {
+                temp: 20.0,
+                rpm: 600,
+            },
+            TempRpm {
+                temp: 30.5,
+                rpm: 800,
+            },
+            TempRpm {
+                temp: 40.0,
+                rpm: 1000,
+            },
+            TempRpm {
+                temp: 50.0,
+                rpm: 1200,
+            },
+            TempRpm {
+                temp: 60.0,
+                rpm: 1500,
+            },
+            TempRpm {
+                temp: 70.0,
+                rpm: 2000,
+            },
+        ];
+        let fans = device.fans();
+        fans[0] = Fan::Pwm(0.5);
+        fans[1] = Fan::Rpm(1200);
+        fans[2] = Fan::Curve(1, curve);
+        fans[3] = Fan::Pwm(1.5);
+        device.update_fans().unwrap();
+
+        let reports = reports(&transport);
+        assert_eq!(reports.len(), 6);
+        assert_eq!(reports[0], vec![0x23, 0, 50]);
+        assert_eq!(
+            reports[1],
+            vec![
+                0x25, 1, 0, // channel and probe
+                0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, // temperatures
+                0x04, 0xb0, 0x04, 0xb0, 0x04, 0xb0, 0x04, 0xb0, 0x04, 0xb0, 0x04, 0xb0,
+            ]
+        );
+        assert_eq!(
+            reports[2],
+            vec![
+                0x25, 2, 1, // channel and probe
+                0x07, 0xd0, 0x0b, 0xea, 0x0f, 0xa0, 0x13, 0x88, 0x17, 0x70, 0x1b, 0x58, 0x02, 0x58,
+                0x03, 0x20, 0x03, 0xe8, 0x04, 0xb0, 0x05, 0xdc, 0x07, 0xd0,
+            ]
+        );
+        // duties are clamped to 100%
+        assert_eq!(reports[3], vec![0x23, 3, 100]);
+        assert_eq!(reports[4], vec![0x23, 4, 25]);
+        assert_eq!(reports[5], vec![0x23, 5, 25]);
+    }
+
+    #[test]
+    fn update_strips_layout() {
+        let (mut device, transport) = commander_pro();
+        let red = Color::Rgb(1.0, 0.0, 0.0);
+        let blue = Color::Rgb(0.0, 0.0, 1.0);
+        let strips = device.strips();
+        strips[0].colors = vec![red; 60];
+        strips[1].hardware = Some(HardwareEffect::Static(blue));
+        device.update_strips().unwrap();
+
+        let written = reports(&transport);
+        let direct = |start: u8, count: u8, component: u8, value: u8| {
+            let mut report = vec![0x32, 0, start, count, component];
+            if value > 0 {
+                report.extend(vec![value; count as usize]);
+            }
+            report
+        };
+        assert_eq!(
+            written,
+            vec![
+                vec![0x38, 0, 2],
+                direct(0, 50, 0, 255),
+                direct(0, 50, 1, 0),
+                direct(0, 50, 2, 0),
+                direct(50, 10, 0, 255),
+                direct(50, 10, 1, 0),
+                direct(50, 10, 2, 0),
+                vec![0x33],
+                vec![0x37, 1],
+                vec![0x34, 1],
+                vec![0x38, 1, 1],
+                vec![0x35, 1, 0, 204, 4, 1, 1, 0, 0xff, 0, 0, 255],
+                vec![0x33, 1],
+            ]
+        );
+
+        // the firmware keeps running the effect it already has
+        device.update_strips().unwrap();
+        let again = reports(&transport);
+        assert_eq!(again.len(), 8);
+        assert!(again.iter().all(|report| report.get(1) != Some(&1)));
+    }
+
+    #[test]
+    fn invalid_replies_count_as_failed_reads() {
+        let (mut device, transport) = commander_pro();
+        transport.respond(&[0, 0x11, 0], &[0xff]);
+        transport.respond(&[0, 0x21], &[0, 0x04]);
+        device.initialize().unwrap();
+        assert_eq!(device.probes()[0], Some(FAILED_PROBE_TEMP));
+        assert_eq!(device.probes()[4], Some(12.0));
+
+        // every request is tried three times
+        let probe = reports(&transport)
+            .into_iter()
+            .filter(|report| report == &[0x11])
+            .count();
+        assert_eq!(probe, 3);
+
+        // the fans are sampled after the probe and the three rails
+        for _ in 0..5 {
+            device.update().unwrap();
+        }
+        assert_eq!(device.rpms()[0], None);
+    }
+
+    #[test]
+    fn update_fails_when_unplugged() {
+        let (mut device, transport) = commander_pro();
+        device.initialize().unwrap();
+        device.update().unwrap();
+
+        // nothing is left to write, but the probes still need to be sampled
+        transport.unplug();
+        assert!(device.update().is_err());
+    }
 }
@@ -0,0 +1,423 @@
+use anyhow::*;
+
+pub const REPORT_LENGTH: usize = 64;
+pub const RESPONSE_LENGTH: usize = 16;
+
+const CMD_GET_FIRMWARE: u8 = 0x02;
+const CMD_GET_BOOTLOADER: u8 = 0x06;
+const CMD_GET_TEMP_CONFIG: u8 = 0x10;
+const CMD_GET_TEMP: u8 = 0x11;
+const CMD_GET_VOLTS: u8 = 0x12;
+const CMD_GET_FAN_MODES: u8 = 0x20;
+const CMD_GET_FAN_RPM: u8 = 0x21;
+const CMD_SET_FAN_DUTY: u8 = 0x23;
+const CMD_SET_FAN_PROFILE: u8 = 0x25;
+
+const CMD_LED_DIRECT: u8 = 0x32;
+const CMD_LED_COMMIT: u8 = 0x33;
+const CMD_BEGIN_LED_EFFECT: u8 = 0x34;
+const CMD_LED_EFFECT: u8 = 0x35;
+const CMD_RESET_LED_CHANNEL: u8 = 0x37;
+const CMD_SET_LED_CHANNEL_STATE: u8 = 0x38;
+
+const LED_PORT_STATE_HARDWARE: u8 = 0x01;
+const LED_PORT_STATE_SOFTWARE: u8 = 0x02;
+
+pub const LED_SPEED_FAST: u8 = 0x00;
+pub const LED_SPEED_MEDIUM: u8 = 0x01;
+pub const LED_SPEED_SLOW: u8 = 0x02;
+
+pub const LED_DIRECTION_FORWARD: u8 = 0x01;
+pub const LED_DIRECTION_BACKWARD: u8 = 0x00;
+
+pub const FAN_MODE_DISCONNECTED: u8 = 0x00;
+pub const FAN_MODE_DC: u8 = 0x01;
+pub const FAN_MODE_PWM: u8 = 0x02;
+
+const STATUS_OK: u8 = 0x00;
+
+/// A report of the Commander PRO and Lighting Node protocol. Every report is answered with a
+/// reply that starts with a status byte, followed by the data that was asked for.
+#[derive(Clone, Debug)]
+pub enum Command<'a> {
+    GetFirmware,
+    GetBootloader,
+    GetTempConfig,
+    GetTemp(u8),
+    GetVolts(u8),
+    GetFanModes,
+    GetFanRpm(u8),
+    SetFanDuty {
+        channel: u8,
+        percent: u8,
+    },
+    /// Six (temperature, rpm) points, driven by one of the temperature probes.
+    SetFanProfile {
+        channel: u8,
+        probe: u8,
+        points: [(f32, u16); 6],
+    },
+    ResetLedChannel(u8),
+    BeginLedEffect(u8),
+    SetLedChannelState {
+        channel: u8,
+        hardware: bool,
+    },
+    LedEffect(LedEffect),
+    LedCommit(u8),
+    /// One color component of up to 50 leds, starting at `start`.
+    LedDirect {
+        channel: u8,
+        start: u8,
+        component: u8,
+        values: &'a [u8],
+    },
+}
+
+#[derive(Clone, Debug)]
+pub struct LedEffect {
+    pub channel: u8,
+    pub led_count: u8,
+    pub mode: u8,
+    pub speed: u8,
+    pub direction: u8,
+    /// Up to three colors. The firmware picks random colors when this is empty.
+    pub colors: Vec<[u8; 3]>,
+    /// Temperatures for the colors of the temperature effect.
+    pub temperatures: [f32; 3],
+}
+
+/// A reply with its status byte checked and stripped.
+pub trait Response: Sized {
+    /// The number of data bytes the reply needs.
+    const LENGTH: usize;
+
+    fn decode(data: &[u8]) -> Self;
+}
+
+#[derive(Debug)]
+pub struct FirmwareVersion(pub u8, pub u8, pub u8);
+
+#[derive(Debug)]
+pub struct BootloaderVersion(pub u8, pub u8);
+
+/// Whether each of the four temperature probes is connected.
+pub struct TempConfig(pub [bool; 4]);
+
+pub struct Temperature(pub f32);
+
+pub struct Voltage(pub f32);
+
+/// The raw mode byte of each of the six fan channels.
+pub struct FanModes(pub [u8; 6]);
+
+pub struct Rpm(pub u16);
+
+/// The reply to a command that only reports its status.
+pub struct Ack;
+
+fn fixed_point(temp: f32) -> [u8; 2] {
+    ((temp * 100.0) as u16).to_be_bytes()
+}
+
+impl Command<'_> {
+    pub fn encode(&self) -> [u8; REPORT_LENGTH] {
+        let mut buf = [0u8; REPORT_LENGTH];
+        // the first byte is the report id
+        let data = &mut buf[1..];
+        match self {
+            Command::GetFirmware => data[0] = CMD_GET_FIRMWARE,
+            Command::GetBootloader => data[0] = CMD_GET_BOOTLOADER,
+            Command::GetTempConfig => data[0] = CMD_GET_TEMP_CONFIG,
+            Command::GetTemp(probe) => data[..2].copy_from_slice(&[CMD_GET_TEMP, *probe]),
+            Command::GetVolts(rail) => data[..2].copy_from_slice(&[CMD_GET_VOLTS, *rail]),
+            Command::GetFanModes => data[0] = CMD_GET_FAN_MODES,
+            Command::GetFanRpm(channel) => data[..2].copy_from_slice(&[CMD_GET_FAN_RPM, *channel]),
+            Command::SetFanDuty { channel, percent } => {
+                data[..3].copy_from_slice(&[CMD_SET_FAN_DUTY, *channel, (*percent).min(100)])
+            }
+            Command::SetFanProfile {
+                channel,
+                probe,
+                points,
+            } => {
+                data[..3].copy_from_slice(&[CMD_SET_FAN_PROFILE, *channel, *probe]);
+                for (i, &(temp, rpm)) in points.iter().enumerate() {
+                    data[3 + i * 2..5 + i * 2].copy_from_slice(&fixed_point(temp));
+                    data[15 + i * 2..17 + i * 2].copy_from_slice(&rpm.to_be_bytes());
+                }
+            }
+            Command::ResetLedChannel(channel) => {
+                data[..2].copy_from_slice(&[CMD_RESET_LED_CHANNEL, *channel])
+            }
+            Command::BeginLedEffect(channel) => {
+                data[..2].copy_from_slice(&[CMD_BEGIN_LED_EFFECT, *channel])
+            }
+            Command::SetLedChannelState { channel, hardware } => {
+                let state = if *hardware {
+                    LED_PORT_STATE_HARDWARE
+                } else {
+                    LED_PORT_STATE_SOFTWARE
+                };
+                data[..3].copy_from_slice(&[CMD_SET_LED_CHANNEL_STATE, *channel, state]);
+            }
+            Command::LedEffect(effect) => {
+                data[..9].copy_from_slice(&[
+                    CMD_LED_EFFECT,
+                    effect.channel,
+                    0, // start led
+                    effect.led_count,
+                    effect.mode,
+                    effect.speed,
+                    effect.direction,
+                    effect.colors.is_empty() as u8, // random colors
+                    0xff,                           // brightness
+                ]);
+                for (i, color) in effect.colors.iter().take(3).enumerate() {
+                    data[9 + i * 3..12 + i * 3].copy_from_slice(color);
+                }
+                for (i, &temp) in effect.temperatures.iter().enumerate() {
+                    data[18 + i * 2..20 + i * 2].copy_from_slice(&fixed_point(temp));
+                }
+            }
+            Command::LedCommit(channel) => data[..2].copy_from_slice(&[CMD_LED_COMMIT, *channel]),
+            Command::LedDirect {
+                channel,
+                start,
+                component,
+                values,
+            } => {
+                let values = &values[..values.len().min(50)];
+                data[..5].copy_from_slice(&[
+                    CMD_LED_DIRECT,
+                    *channel,
+                    *start,
+                    values.len() as u8,
+                    *component,
+                ]);
+                data[5..5 + values.len()].copy_from_slice(values);
+            }
+        }
+        buf
+    }
+
+    /// Check and decode the reply to this command.
+    pub fn decode<R: Response>(&self, reply: &[u8]) -> Result<R> {
+        decode(reply).with_context(|| format!("{:?}", self))
+    }
+}
+
+/// Check the status byte and length of a reply, and decode it.
+pub fn decode<R: Response>(reply: &[u8]) -> Result<R> {
+    match reply.first() {
+        None => bail!("No reply"),
+        Some(&STATUS_OK) => {}
+        Some(status) => bail!("Device returned status {:#04x}", status),
+    }
+    if reply.len() < 1 + R::LENGTH {
+        bail!(
+            "Reply of {} bytes is too short, expected {}",
+            reply.len(),
+            1 + R::LENGTH
+        );
+    }
+    Ok(R::decode(&reply[1..]))
+}
+
+impl Response for FirmwareVersion {
+    const LENGTH: usize = 3;
+
+    fn decode(data: &[u8]) -> Self {
+        FirmwareVersion(data[0], data[1], data[2])
+    }
+}
+
+impl Response for BootloaderVersion {
+    const LENGTH: usize = 2;
+
+    fn decode(data: &[u8]) -> Self {
+        BootloaderVersion(data[0], data[1])
+    }
+}
+
+impl Response for TempConfig {
+    const LENGTH: usize = 4;
+
+    fn decode(data: &[u8]) -> Self {
+        TempConfig([data[0] > 0, data[1] > 0, data[2] > 0, data[3] > 0])
+    }
+}
+
+impl Response for Temperature {
+    const LENGTH: usize = 2;
+
+    fn decode(data: &[u8]) -> Self {
+        Temperature(u16::from_be_bytes([data[0], data[1]]) as f32 / 100.0)
+    }
+}
+
+impl Response for Voltage {
+    const LENGTH: usize = 2;
+
+    fn decode(data: &[u8]) -> Self {
+        Voltage(u16::from_be_bytes([data[0], data[1]]) as f32 / 1000.0)
+    }
+}
+
+impl Response for FanModes {
+    const LENGTH: usize = 6;
+
+    fn decode(data: &[u8]) -> Self {
+        let mut modes = [0; 6];
+        modes.copy_from_slice(&data[..6]);
+        FanModes(modes)
+    }
+}
+
+impl Response for Rpm {
+    const LENGTH: usize = 2;
+
+    fn decode(data: &[u8]) -> Self {
+        Rpm(u16::from_be_bytes([data[0], data[1]]))
+    }
+}
+
+impl Response for Ack {
+    const LENGTH: usize = 0;
+
+    fn decode(_data: &[u8]) -> Self {
+        Ack
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_simple_commands() {
+        let report = Command::GetTemp(2).encode();
+        assert_eq!(report.len(), REPORT_LENGTH);
+        assert_eq!(report[..3], [0, CMD_GET_TEMP, 2]);
+        assert!(report[3..].iter().all(|&byte| byte == 0));
+
+        let report = Command::SetFanDuty {
+            channel: 5,
+            percent: 150,
+        }
+        .encode();
+        assert_eq!(report[..4], [0, CMD_SET_FAN_DUTY, 5, 100]);
+
+        let report = Command::SetLedChannelState {
+            channel: 1,
+            hardware: false,
+        }
+        .encode();
+        assert_eq!(
+            report[..4],
+            [0, CMD_SET_LED_CHANNEL_STATE, 1, LED_PORT_STATE_SOFTWARE]
+        );
+    }
+
+    #[test]
+    fn encode_fan_profile() {
+        let mut points = [(0.0, 0); 6];
+        for (i, point) in points.iter_mut().enumerate() {
+            *point = (25.5 + i as f32 * 5.0, 500 + i as u16 * 300);
+        }
+        let report = Command::SetFanProfile {
+            channel: 3,
+            probe: 1,
+            points,
+        }
+        .encode();
+
+        assert_eq!(report[1..4], [CMD_SET_FAN_PROFILE, 3, 1]);
+        // temperatures in hundredths of a degree, then the rpms, all big endian
+        assert_eq!(report[4..6], [0x09, 0xf6]);
+        assert_eq!(report[14..16], [0x13, 0xba]);
+        assert_eq!(report[16..18], [0x01, 0xf4]);
+        assert_eq!(report[26..28], [0x07, 0xd0]);
+        assert!(report[28..].iter().all(|&byte| byte == 0));
+    }
+
+    #[test]
+    fn encode_led_effect() {
+        let effect = LedEffect {
+            channel: 1,
+            led_count: 204,
+            mode: 0x05,
+            speed: LED_SPEED_SLOW,
+            direction: LED_DIRECTION_BACKWARD,
+            colors: vec![[1, 2, 3], [4, 5, 6], [7, 8, 9], [10, 11, 12]],
+            temperatures: [30.0, 40.0, 50.0],
+        };
+        let report = Command::LedEffect(effect.clone()).encode();
+        assert_eq!(
+            report[1..10],
+            [CMD_LED_EFFECT, 1, 0, 204, 0x05, LED_SPEED_SLOW, 0, 0, 0xff]
+        );
+        // only three colors fit
+        assert_eq!(report[10..19], [1, 2, 3, 4, 5, 6, 7, 8, 9]);
+        assert_eq!(report[19..25], [0x0b, 0xb8, 0x0f, 0xa0, 0x13, 0x88]);
+
+        // without colors the firmware picks random ones
+        let report = Command::LedEffect(LedEffect {
+            colors: vec![],
+            ..effect
+        })
+        .encode();
+        assert_eq!(report[8], 1);
+        assert!(report[10..19].iter().all(|&byte| byte == 0));
+    }
+
+    #[test]
+    fn encode_led_direct() {
+        let values: Vec<u8> = (1..=60).collect();
+        let report = Command::LedDirect {
+            channel: 1,
+            start: 50,
+            component: 2,
+            values: &values,
+        }
+        .encode();
+        // at most 50 leds fit in a report
+        assert_eq!(report[1..6], [CMD_LED_DIRECT, 1, 50, 50, 2]);
+        assert_eq!(report[6..56], values[..50]);
+        assert!(report[56..].iter().all(|&byte| byte == 0));
+    }
+
+    #[test]
+    fn decode_replies() {
+        let FirmwareVersion(major, minor, patch) = decode(&[0, 0, 9, 129, 0xaa]).unwrap();
+        assert_eq!((major, minor, patch), (0, 9, 129));
+
+        let TempConfig(connected) = decode(&[0, 1, 0, 2, 0]).unwrap();
+        assert_eq!(connected, [true, false, true, false]);
+
+        let Temperature(temp) = decode(&[0, 0x0b, 0xea]).unwrap();
+        assert_eq!(temp, 30.5);
+
+        let Voltage(volts) = decode(&[0, 0x13, 0x9c]).unwrap();
+        assert_eq!(volts, 5.02);
+
+        let FanModes(modes) = decode(&[0, 2, 1, 0, 0, 0, 2]).unwrap();
+        assert_eq!(modes, [FAN_MODE_PWM, FAN_MODE_DC, 0, 0, 0, FAN_MODE_PWM]);
+
+        let Rpm(rpm) = decode(&[0, 0x04, 0xb0]).unwrap();
+        assert_eq!(rpm, 1200);
+
+        assert!(decode::<Ack>(&[0]).is_ok());
+    }
+
+    #[test]
+    fn decode_rejects_bad_replies() {
+        assert!(decode::<Ack>(&[]).is_err());
+        assert!(decode::<Ack>(&[0x12]).is_err());
+        assert!(decode::<Rpm>(&[0, 0x04]).is_err());
+        assert!(decode::<FanModes>(&[0, 2, 1, 0, 0, 0]).is_err());
+
+        let error = Command::GetFanRpm(0).decode::<Rpm>(&[0x12]).err().unwrap();
+        assert!(format!("{:?}", error).contains("GetFanRpm"));
+    }
+}
@@ -2,23 +2,225 @@ use anyhow::Result;
 use serde::Deserialize;
 
 use crate::color::Color;
+use crate::identity::{DeviceNames, DeviceRef};
 
-#[derive(Clone, Deserialize, Debug)]
+#[derive(Clone, Deserialize, Debug, PartialEq)]
 pub enum Fan {
     Pwm(f32),
     Rpm(u16),
     Curve(usize, [TempRpm; 6]),
 }
 
-#[derive(Clone, Deserialize, Debug)]
+#[derive(Clone, Deserialize, Debug, PartialEq)]
 pub struct TempRpm {
     pub temp: f32,
     pub rpm: u16,
 }
 
-#[derive(Clone)]
+/// Remembers the fan setting of every rpm sample in a row that read zero, so that a stall can be
+/// told apart from a fan that was allowed to stand still.
+#[derive(Clone, Default)]
+pub struct StallCounter {
+    zero_samples: Vec<Fan>,
+}
+
+const STALL_HISTORY: usize = 64;
+
+/// The state the hardware is left in when the service exits, so the controllers keep the lights
+/// and fans going on their own.
+#[derive(Deserialize, Clone, Debug)]
+pub struct Shutdown {
+    /// Hardware effect for every strip. It is also shown on strips that no color profile uses.
+    #[serde(default)]
+    pub effect: HardwareEffect,
+    /// Hardware effects for specific channels, in place of `effect`.
+    #[serde(default)]
+    pub channels: Vec<ChannelEffect>,
+    /// Hardware curve for every fan, driven by the first temperature probe of the device.
+    #[serde(default = "default_safe_curve")]
+    pub fan_curve: [TempRpm; 6],
+    /// Duty cycle for fans on devices that have no temperature probe to drive the curve.
+    #[serde(default = "default_safe_duty")]
+    pub fan_duty: f32,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct ChannelEffect {
+    pub device: DeviceRef,
+    pub channel: usize,
+    pub effect: HardwareEffect,
+}
+
+/// The lighting effects built into the Commander PRO and Lighting Node firmware. Effects that
+/// take a pair of colors use random colors when they are left out.
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub enum HardwareEffect {
+    RainbowWave {
+        #[serde(default)]
+        speed: LedSpeed,
+        #[serde(default)]
+        direction: LedDirection,
+    },
+    ColorShift {
+        #[serde(default)]
+        speed: LedSpeed,
+        #[serde(default)]
+        colors: Option<(Color, Color)>,
+    },
+    ColorPulse {
+        #[serde(default)]
+        speed: LedSpeed,
+        #[serde(default)]
+        colors: Option<(Color, Color)>,
+    },
+    ColorWave {
+        #[serde(default)]
+        speed: LedSpeed,
+        #[serde(default)]
+        direction: LedDirection,
+        #[serde(default)]
+        colors: Option<(Color, Color)>,
+    },
+    Static(Color),
+    /// Fades between three colors at the given temperatures.
+    Temperature([(f32, Color); 3]),
+    Visor {
+        #[serde(default)]
+        speed: LedSpeed,
+        #[serde(default)]
+        direction: LedDirection,
+        #[serde(default)]
+        colors: Option<(Color, Color)>,
+    },
+    Marquee {
+        #[serde(default)]
+        speed: LedSpeed,
+        #[serde(default)]
+        direction: LedDirection,
+        color: Color,
+    },
+    Blink {
+        #[serde(default)]
+        speed: LedSpeed,
+        #[serde(default)]
+        colors: Option<(Color, Color)>,
+    },
+    Sequential {
+        #[serde(default)]
+        speed: LedSpeed,
+        #[serde(default)]
+        direction: LedDirection,
+        color: Color,
+    },
+    Rainbow {
+        #[serde(default)]
+        speed: LedSpeed,
+    },
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+pub enum LedSpeed {
+    Fast,
+    #[default]
+    Medium,
+    Slow,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+pub enum LedDirection {
+    #[default]
+    Forward,
+    Backward,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self {
+            effect: HardwareEffect::default(),
+            channels: vec![],
+            fan_curve: default_safe_curve(),
+            fan_duty: default_safe_duty(),
+        }
+    }
+}
+
+impl Shutdown {
+    pub fn resolve(&mut self, devices: &DeviceNames) -> Result<()> {
+        for config in self.channels.iter_mut() {
+            devices.resolve(&mut config.device)?;
+        }
+        Ok(())
+    }
+
+    /// The settings for the device at `device` in the device list, with only its own channels.
+    pub fn for_device(&self, device: usize) -> Shutdown {
+        Shutdown {
+            channels: self
+                .channels
+                .iter()
+                .filter(|config| config.device.is(device))
+                .cloned()
+                .collect(),
+            ..self.clone()
+        }
+    }
+
+    /// The hardware effect for a channel, of the device that these settings are for.
+    pub fn effect(&self, channel: usize) -> &HardwareEffect {
+        self.channels
+            .iter()
+            .find(|config| config.channel == channel)
+            .map_or(&self.effect, |config| &config.effect)
+    }
+}
+
+impl Default for HardwareEffect {
+    fn default() -> Self {
+        HardwareEffect::Visor {
+            speed: LedSpeed::Medium,
+            direction: LedDirection::Forward,
+            colors: None,
+        }
+    }
+}
+
+fn default_safe_curve() -> [TempRpm; 6] {
+    let point = |temp, rpm| TempRpm { temp, rpm };
+    [
+        point(25.0, 800),
+        point(30.0, 1000),
+        point(35.0, 1300),
+        point(40.0, 1600),
+        point(45.0, 2000),
+        point(50.0, 3000),
+    ]
+}
+
+fn default_safe_duty() -> f32 {
+    0.6
+}
+
+#[derive(Clone, Default)]
 pub struct Strip {
     pub colors: Vec<Color>,
+    /// Set while the whole channel is handed to an effect built into the firmware.
+    pub hardware: Option<HardwareEffect>,
+    /// The number of leds, for devices that know how many are connected.
+    pub leds: Option<usize>,
+}
+
+impl Strip {
+    /// The colors of `leds` leds, for strips without firmware that runs effects. A static
+    /// hardware effect is shown as its color, and other hardware effects leave the strip dark.
+    pub fn render(&self, leds: usize) -> Vec<Color> {
+        let mut colors = match self.hardware.as_ref() {
+            Some(HardwareEffect::Static(color)) => vec![*color; leds],
+            Some(_) => vec![],
+            None => self.colors.clone(),
+        };
+        colors.resize(leds, Color::Rgb(0.0, 0.0, 0.0));
+        colors
+    }
 }
 
 pub trait Device {
@@ -28,13 +230,118 @@ pub trait Device {
 
     fn name(&self) -> &str;
 
+    /// Something that tells this device apart from others of the same kind, and that stays the
+    /// same across restarts, such as a serial number.
+    fn identity(&self) -> Option<String> {
+        None
+    }
+
     fn fans(&mut self) -> &mut [Fan];
 
     fn strips(&mut self) -> &mut [Strip];
 
     fn probes(&self) -> &[Option<f32>];
 
+    /// Names for the probes, unique within the device. Sensors are addressed as `device/name`.
+    fn probe_names(&self) -> Vec<String> {
+        (1..=self.probes().len())
+            .map(|i| format!("temp{}", i))
+            .collect()
+    }
+
+    /// How many of the probes, counting from the first, hardware fan curves can follow. Probes
+    /// past these, like voltage rails, can only be used by software.
+    fn curve_probes(&self) -> usize {
+        self.probes().len()
+    }
+
+    /// The measured speed of every fan channel, or `None` for channels that aren't connected or
+    /// haven't been sampled yet. These are published as sensors called `device/fanN`.
+    fn rpms(&self) -> Vec<Option<u16>> {
+        vec![]
+    }
+
+    /// Fan channels that read 0 rpm for at least `samples` samples in a row while being driven at
+    /// `min_duty` or more. Channels that aren't connected are never reported.
+    fn stalled_fans(&self, _samples: usize, _min_duty: f32) -> Vec<usize> {
+        vec![]
+    }
+
     fn report_status(&self);
 
     fn update(&mut self) -> Result<()>;
+
+    /// Show the idle hardware effects of `shutdown` on every strip, until a color profile takes
+    /// the strip over.
+    fn idle(&mut self, shutdown: &Shutdown) {
+        for (channel, strip) in self.strips().iter_mut().enumerate() {
+            strip.hardware = Some(shutdown.effect(channel).clone());
+        }
+    }
+
+    /// Hand the lights and fans back to the hardware before the service exits.
+    fn shutdown(&mut self, _shutdown: &Shutdown) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl Fan {
+    /// Whether the fan is told to run at `min_duty` or more. Rpm targets and curves count as
+    /// driven as long as they ask for any speed at all.
+    pub fn is_driven(&self, min_duty: f32) -> bool {
+        match self {
+            &Fan::Pwm(duty) => duty >= min_duty,
+            &Fan::Rpm(rpm) => rpm > 0,
+            Fan::Curve(_, curve) => curve.iter().any(|point| point.rpm > 0),
+        }
+    }
+}
+
+impl StallCounter {
+    pub fn sample(&mut self, fan: &Fan, rpm: u16) {
+        if rpm > 0 {
+            self.zero_samples.clear();
+        } else {
+            if self.zero_samples.len() == STALL_HISTORY {
+                self.zero_samples.remove(0);
+            }
+            self.zero_samples.push(fan.clone());
+        }
+    }
+
+    /// Whether the last `samples` samples all read zero while the fan was driven.
+    pub fn is_stalled(&self, samples: usize, min_duty: f32) -> bool {
+        self.zero_samples
+            .iter()
+            .rev()
+            .take_while(|fan| fan.is_driven(min_duty))
+            .count()
+            >= samples
+    }
+}
+
+/// The channels of all counters that consider their fan stalled.
+pub fn stalled(counters: &[StallCounter], samples: usize, min_duty: f32) -> Vec<usize> {
+    counters
+        .iter()
+        .enumerate()
+        .filter(|(_, counter)| counter.is_stalled(samples, min_duty))
+        .map(|(channel, _)| channel)
+        .collect()
+}
+
+/// Interpolate the rpm a fan curve asks for at the given temperature.
+pub fn curve_rpm(curve: &[TempRpm], temp: f32) -> f32 {
+    if temp <= curve[0].temp {
+        return curve[0].rpm as f32;
+    }
+
+    for pair in curve.windows(2) {
+        if temp <= pair[1].temp {
+            let x = (temp - pair[0].temp) / (pair[1].temp - pair[0].temp).max(f32::EPSILON);
+            return pair[0].rpm as f32 + (pair[1].rpm as f32 - pair[0].rpm as f32) * x;
+        }
+    }
+
+    curve[curve.len() - 1].rpm as f32
 }
@@ -0,0 +1,219 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::net::{Ipv4Addr, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::*;
+use serde::Deserialize;
+
+/// Where DMX universes are received from, so lighting consoles can drive the strips.
+#[derive(Deserialize, Clone, Debug)]
+pub struct DmxConfig {
+    pub protocol: DmxProtocol,
+    /// The address to listen on. Defaults to every interface, on the port of the protocol.
+    #[serde(default)]
+    pub bind: Option<String>,
+    /// Seconds after the last packet that a universe counts as gone.
+    #[serde(default = "default_timeout")]
+    pub timeout: f32,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum DmxProtocol {
+    /// Streaming ACN, also known as sACN. Multicast groups are joined for every universe in use.
+    E131,
+    ArtNet,
+}
+
+/// The universes received so far, shared with the thread that receives them.
+#[derive(Clone)]
+pub struct DmxInput {
+    protocol: DmxProtocol,
+    socket: Arc<UdpSocket>,
+    universes: Arc<Mutex<HashMap<u16, Universe>>>,
+    subscribed: Arc<Mutex<HashSet<u16>>>,
+    timeout: Duration,
+}
+
+struct Universe {
+    channels: Vec<u8>,
+    received: Instant,
+}
+
+const E131_PORT: u16 = 5568;
+const E131_IDENTIFIER: &[u8; 12] = b"ASC-E1.17\0\0\0";
+const E131_VECTOR_ROOT_DATA: u32 = 0x0000_0004;
+const E131_VECTOR_FRAMING_DATA: u32 = 0x0000_0002;
+const E131_VECTOR_DMP_SET_PROPERTY: u8 = 0x02;
+/// The header up to and including the start code.
+const E131_HEADER: usize = 126;
+
+const ARTNET_PORT: u16 = 6454;
+const ARTNET_IDENTIFIER: &[u8; 8] = b"Art-Net\0";
+const ARTNET_OP_DMX: u16 = 0x5000;
+const ARTNET_HEADER: usize = 18;
+
+const DMX_START_CODE: u8 = 0x00;
+pub const UNIVERSE_SIZE: usize = 512;
+
+fn default_timeout() -> f32 {
+    2.0
+}
+
+fn be16(data: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes([data[offset], data[offset + 1]])
+}
+
+/// The universe and first channel of every led, three channels each. A led that doesn't fit in
+/// the rest of a universe starts at the beginning of the next one, like most consoles lay out
+/// pixels. `start` counts from 0 here.
+pub fn addresses(universe: u16, start: usize, leds: usize) -> Vec<(u16, usize)> {
+    let mut universe = universe;
+    let mut channel = start;
+    let mut addresses = Vec::with_capacity(leds);
+    for _ in 0..leds {
+        while channel + 3 > UNIVERSE_SIZE {
+            universe = universe.wrapping_add(1);
+            channel -= UNIVERSE_SIZE.min(channel);
+        }
+        addresses.push((universe, channel));
+        channel += 3;
+    }
+    addresses
+}
+
+/// The universe and channels of an E1.31 data packet.
+fn parse_e131(packet: &[u8]) -> Option<(u16, &[u8])> {
+    if packet.len() < E131_HEADER
+        || &packet[4..16] != E131_IDENTIFIER
+        || u32::from_be_bytes([packet[18], packet[19], packet[20], packet[21]])
+            != E131_VECTOR_ROOT_DATA
+        || u32::from_be_bytes([packet[40], packet[41], packet[42], packet[43]])
+            != E131_VECTOR_FRAMING_DATA
+        || packet[117] != E131_VECTOR_DMP_SET_PROPERTY
+        || packet[125] != DMX_START_CODE
+    {
+        return None;
+    }
+
+    let universe = be16(packet, 113);
+    // the property count includes the start code
+    let count = (be16(packet, 123) as usize).saturating_sub(1);
+    let end = (E131_HEADER + count).min(packet.len());
+    Some((universe, &packet[E131_HEADER..end]))
+}
+
+/// The universe and channels of an ArtDmx packet. The universe is the 15 bit port address.
+fn parse_artnet(packet: &[u8]) -> Option<(u16, &[u8])> {
+    if packet.len() < ARTNET_HEADER
+        || &packet[..8] != ARTNET_IDENTIFIER
+        || u16::from_le_bytes([packet[8], packet[9]]) != ARTNET_OP_DMX
+    {
+        return None;
+    }
+
+    let universe = u16::from_le_bytes([packet[14], packet[15]]) & 0x7fff;
+    let length = be16(packet, 16) as usize;
+    let end = (ARTNET_HEADER + length).min(packet.len());
+    Some((universe, &packet[ARTNET_HEADER..end]))
+}
+
+impl DmxInput {
+    /// Start receiving universes in the background.
+    pub fn start(config: &DmxConfig) -> Result<Self> {
+        let timeout = Duration::try_from_secs_f32(config.timeout)
+            .with_context(|| format!("Invalid DMX timeout {}", config.timeout))?;
+        let port = match config.protocol {
+            DmxProtocol::E131 => E131_PORT,
+            DmxProtocol::ArtNet => ARTNET_PORT,
+        };
+        let bind = config
+            .bind
+            .clone()
+            .unwrap_or_else(|| format!("0.0.0.0:{}", port));
+        let socket = UdpSocket::bind(&bind)
+            .with_context(|| format!("Unable to listen for DMX on {}", bind))?;
+
+        let input = Self {
+            protocol: config.protocol,
+            socket: Arc::new(socket),
+            universes: Arc::new(Mutex::new(HashMap::new())),
+            subscribed: Arc::new(Mutex::new(HashSet::new())),
+            timeout,
+        };
+
+        let receiver = input.clone();
+        std::thread::spawn(move || receiver.receive());
+
+        log::info!("Listening for {:?} on {}", config.protocol, bind);
+        Ok(input)
+    }
+
+    fn receive(&self) {
+        let mut buf = [0u8; 1024];
+        loop {
+            let len = match self.socket.recv(&mut buf) {
+                Ok(len) => len,
+                Err(e) => {
+                    log::error!("Unable to receive DMX: {}", e);
+                    std::thread::sleep(Duration::from_secs(1));
+                    continue;
+                }
+            };
+
+            let parsed = match self.protocol {
+                DmxProtocol::E131 => parse_e131(&buf[..len]),
+                DmxProtocol::ArtNet => parse_artnet(&buf[..len]),
+            };
+            if let Some((universe, channels)) = parsed {
+                let mut universes = self.universes.lock().unwrap();
+                let entry = universes.entry(universe).or_insert_with(|| Universe {
+                    channels: vec![0; UNIVERSE_SIZE],
+                    received: Instant::now(),
+                });
+                entry.channels[..channels.len().min(UNIVERSE_SIZE)]
+                    .copy_from_slice(&channels[..channels.len().min(UNIVERSE_SIZE)]);
+                entry.received = Instant::now();
+            }
+        }
+    }
+
+    /// Make sure packets for a universe arrive. E1.31 sends every universe to its own multicast
+    /// group.
+    pub fn subscribe(&self, universe: u16) {
+        if self.protocol == DmxProtocol::E131 && self.subscribed.lock().unwrap().insert(universe) {
+            let [hi, lo] = universe.to_be_bytes();
+            let group = Ipv4Addr::new(239, 255, hi, lo);
+            if let Err(e) = self
+                .socket
+                .join_multicast_v4(&group, &Ipv4Addr::UNSPECIFIED)
+            {
+                log::warn!("Unable to join {} for universe {}: {}", group, universe, e);
+            }
+        }
+    }
+
+    /// The channels of a universe, unless nothing was received for it recently.
+    pub fn channels(&self, universe: u16) -> Option<Vec<u8>> {
+        let universes = self.universes.lock().unwrap();
+        universes
+            .get(&universe)
+            .filter(|universe| universe.received.elapsed() < self.timeout)
+            .map(|universe| universe.channels.clone())
+    }
+
+    pub fn is_active(&self, universe: u16) -> bool {
+        let universes = self.universes.lock().unwrap();
+        universes
+            .get(&universe)
+            .is_some_and(|universe| universe.received.elapsed() < self.timeout)
+    }
+}
+
+/// Effects and triggers are cloned and printed along with the config.
+impl fmt::Debug for DmxInput {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "DmxInput({:?})", self.protocol)
+    }
+}
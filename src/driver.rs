@@ -0,0 +1,100 @@
+use hidapi::{DeviceInfo, HidApi};
+
+use crate::device::Device;
+use crate::transport::Transport;
+
+/// Describes a HID controller that one of the backends knows how to drive.
+#[derive(Clone, Copy)]
+pub struct Driver {
+    pub name: &'static str,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    /// The USB interface to open, for controllers that have more than one.
+    pub interface: Option<i32>,
+    /// Builds the driver around the transport. Drivers only talk to the device once they are
+    /// initialized, so building one around `Unplugged` shows the fans, strips and probes it has.
+    pub open: fn(Box<dyn Transport>) -> Box<dyn Device>,
+}
+
+/// Vendors that make RGB or fan controllers, to point out devices that aren't supported yet.
+const RGB_VENDORS: [(u16, &str); 12] = [
+    (0x1b1c, "Corsair"),
+    (0x1e71, "NZXT"),
+    (0x0b05, "ASUS"),
+    (0x1462, "MSI"),
+    (0x048d, "ITE (Gigabyte)"),
+    (0x1532, "Razer"),
+    (0x046d, "Logitech"),
+    (0x1038, "SteelSeries"),
+    (0x0951, "HyperX"),
+    (0x2516, "Cooler Master"),
+    (0x264a, "Thermaltake"),
+    (0x3842, "EVGA"),
+];
+
+impl Driver {
+    pub fn matches(&self, info: &DeviceInfo) -> bool {
+        info.vendor_id() == self.vendor_id
+            && info.product_id() == self.product_id
+            && self
+                .interface
+                .iter()
+                .all(|&interface| info.interface_number() == interface)
+    }
+}
+
+/// Every driver the backends registered.
+#[derive(Default)]
+pub struct Registry {
+    drivers: Vec<Driver>,
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        let mut registry = Self::default();
+        crate::corsair::register(&mut registry);
+        crate::commander_core::register(&mut registry);
+        registry
+    }
+
+    pub fn register(&mut self, driver: Driver) {
+        self.drivers.push(driver);
+    }
+
+    pub fn find(&self, info: &DeviceInfo) -> Option<&Driver> {
+        self.drivers.iter().find(|driver| driver.matches(info))
+    }
+
+    /// Print the supported devices that are plugged in, and any other devices from RGB vendors.
+    pub fn list_devices(&self, api: &HidApi) {
+        println!("Supported devices:");
+        for info in api.device_list() {
+            if let Some(driver) = self.find(info) {
+                println!("  {}", describe(info, driver.name));
+            }
+        }
+
+        println!("Unsupported devices from RGB vendors:");
+        for info in api.device_list() {
+            if self.find(info).is_some() {
+                continue;
+            }
+            if let Some((_, vendor)) = RGB_VENDORS.iter().find(|(id, _)| *id == info.vendor_id()) {
+                let product = info.product_string().unwrap_or("unknown product");
+                println!("  {}", describe(info, &format!("{} {}", vendor, product)));
+            }
+        }
+    }
+}
+
+fn describe(info: &DeviceInfo, name: &str) -> String {
+    format!(
+        "{} [{:04x}:{:04x}] interface {}, serial {}, {}",
+        name,
+        info.vendor_id(),
+        info.product_id(),
+        info.interface_number(),
+        info.serial_number().unwrap_or("none"),
+        info.path().to_string_lossy()
+    )
+}
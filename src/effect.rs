@@ -1,8 +1,13 @@
+use std::cell::Cell;
+
+use anyhow::{anyhow, bail};
 use rand::random;
 use serde::Deserialize;
 
 use crate::color::{Color, ColorOp};
-use crate::device::Strip;
+use crate::device::{HardwareEffect, Strip};
+use crate::dmx::{self, DmxInput};
+use crate::sensor::SensorRef;
 
 #[derive(Deserialize, Clone, Debug)]
 pub enum Effect {
@@ -19,11 +24,14 @@ pub enum Effect {
     },
     Noise(ColorOp),
     Temperature {
-        sensor: usize,
+        sensor: SensorRef,
         min_temperature: f32,
         max_temperature: f32,
         min_color: Color,
         max_color: Color,
+        /// Shown while the sensor has no reading, `max_color` unless set.
+        #[serde(default)]
+        fallback_color: Option<Color>,
         #[serde(default)]
         op: ColorOp,
     },
@@ -41,6 +49,18 @@ pub enum Effect {
         reverse: bool,
         #[serde(default)]
         op: ColorOp,
+        /// Scales the rotation speed with a sensor, for example to spin faster with the pump rpm.
+        #[serde(default)]
+        speed: Option<SensorScale>,
+        #[serde(skip)]
+        phase: Cell<f32>,
+    },
+    /// Switches all leds to the next color every `frames` frames.
+    Flash {
+        frames: usize,
+        colors: Vec<Color>,
+        #[serde(default)]
+        op: ColorOp,
     },
     Pattern {
         #[serde(default)]
@@ -51,6 +71,44 @@ pub enum Effect {
         #[serde(default)]
         op: ColorOp,
     },
+    /// Takes the colors from a DMX universe received over the network, three channels per led
+    /// starting at `start`. Leds past the end of the universe continue in the next one. Without
+    /// recent packets the strip is left alone.
+    Dmx {
+        universe: u16,
+        /// The first channel, counting from 1 like lighting consoles do.
+        #[serde(default = "default_dmx_start")]
+        start: usize,
+        #[serde(default)]
+        op: ColorOp,
+        #[serde(skip)]
+        input: Option<DmxInput>,
+    },
+    /// Hands the whole channel to an effect built into the firmware, until a software effect is
+    /// applied to the channel again.
+    Hardware(HardwareEffect),
+}
+
+/// Maps a sensor value in `min_value..max_value` linearly onto `min..max`.
+#[derive(Deserialize, Clone, Debug)]
+pub struct SensorScale {
+    pub sensor: SensorRef,
+    pub min_value: f32,
+    pub max_value: f32,
+    pub min: f32,
+    pub max: f32,
+}
+
+fn default_dmx_start() -> usize {
+    1
+}
+
+impl SensorScale {
+    pub fn apply(&self, probes: &[Option<f32>]) -> f32 {
+        let value = self.sensor.read(probes).unwrap_or(self.min_value);
+        let x = ((value - self.min_value) / (self.max_value - self.min_value)).clamp(0.0, 1.0);
+        self.min + (self.max - self.min) * x
+    }
 }
 
 impl Effect {
@@ -60,10 +118,57 @@ impl Effect {
             Effect::Temperature { .. } => true,
             Effect::Wave { .. } => true,
             Effect::Rotation { .. } => true,
+            Effect::Flash { .. } => true,
+            Effect::Dmx { .. } => true,
             _ => false,
         }
     }
 
+    pub fn sensors_mut(&mut self) -> Vec<&mut SensorRef> {
+        match self {
+            Effect::Temperature { sensor, .. } => vec![sensor],
+            Effect::Rotation {
+                speed: Some(speed), ..
+            } => vec![&mut speed.sensor],
+            _ => vec![],
+        }
+    }
+
+    /// Checks the settings that would leave the effect without a color to show.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if let Effect::Flash { frames, colors, .. } = self {
+            if *frames == 0 {
+                bail!("The Flash effect needs at least one frame per color");
+            }
+            if colors.is_empty() {
+                bail!("The Flash effect needs at least one color");
+            }
+        }
+        Ok(())
+    }
+
+    /// Hands the DMX input to an effect that reads from it, and makes sure the universes for
+    /// `leds` leds arrive.
+    pub fn connect_dmx(&mut self, dmx: Option<&DmxInput>, leds: usize) -> anyhow::Result<()> {
+        if let Effect::Dmx {
+            universe,
+            start,
+            input,
+            ..
+        } = self
+        {
+            let dmx = dmx.ok_or_else(|| anyhow!("The Dmx effect needs the dmx settings"))?;
+            if *start == 0 {
+                bail!("DMX channels start at 1");
+            }
+            for (universe, _) in dmx::addresses(*universe, *start - 1, leds) {
+                dmx.subscribe(universe);
+            }
+            *input = Some(dmx.clone());
+        }
+        Ok(())
+    }
+
     pub fn apply(
         &self,
         strip: &mut Strip,
@@ -71,6 +176,12 @@ impl Effect {
         indices: &[usize],
         frame: usize,
     ) {
+        if let Effect::Hardware(effect) = self {
+            strip.hardware = Some(effect.clone());
+            return;
+        }
+        strip.hardware = None;
+
         if let Some(required_len) = indices.iter().cloned().max() {
             if strip.colors.len() < required_len + 1 {
                 strip
@@ -99,21 +210,23 @@ impl Effect {
                 }
             }
             &Effect::Temperature {
-                sensor,
+                ref sensor,
                 min_temperature,
                 max_temperature,
                 ref min_color,
                 ref max_color,
+                ref fallback_color,
                 ref op,
             } => {
-                let temp = probes
-                    .get(sensor)
-                    .cloned()
-                    .unwrap_or_default()
-                    .unwrap_or(max_temperature);
-                let range = max_temperature - min_temperature;
-                let x = (temp.min(max_temperature) - min_temperature).max(0.0) / range;
-                let color = min_color.blend(max_color, &ColorOp::Blend(x));
+                // a sensor filter fallback can supply a value before it comes to this
+                let color = match sensor.read(probes) {
+                    Some(temp) => {
+                        let range = max_temperature - min_temperature;
+                        let x = (temp.min(max_temperature) - min_temperature).max(0.0) / range;
+                        min_color.blend(max_color, &ColorOp::Blend(x))
+                    }
+                    None => fallback_color.unwrap_or(*max_color),
+                };
                 for &led in indices.iter() {
                     strip.colors[led] = strip.colors[led].blend(&color, op);
                 }
@@ -143,8 +256,17 @@ impl Effect {
                 ref colors,
                 reverse,
                 ref op,
+                ref speed,
+                ref phase,
             } => {
-                let progress = if reverse {
+                let progress = if let Some(speed) = speed {
+                    phase.set((phase.get() + speed.apply(probes) / duration as f32).fract());
+                    if reverse {
+                        1.0 - phase.get()
+                    } else {
+                        phase.get()
+                    }
+                } else if reverse {
                     (duration - frame % duration) as f32 / duration as f32
                 } else {
                     (frame % duration) as f32 / duration as f32
@@ -161,6 +283,16 @@ impl Effect {
                     strip.colors[led] = strip.colors[led].blend(&color, op);
                 }
             }
+            &Effect::Flash {
+                frames,
+                ref colors,
+                ref op,
+            } => {
+                let color = &colors[(frame / frames) % colors.len()];
+                for &led in indices.iter() {
+                    strip.colors[led] = strip.colors[led].blend(color, op);
+                }
+            }
             &Effect::Pattern {
                 frames_per_led,
                 ref colors,
@@ -183,6 +315,34 @@ impl Effect {
                     strip.colors[led] = strip.colors[led].blend(&color, op);
                 }
             }
+            &Effect::Dmx {
+                universe,
+                start,
+                ref op,
+                ref input,
+            } => {
+                let input = match input {
+                    Some(input) => input,
+                    None => return,
+                };
+                let mut channels: Option<(u16, Option<Vec<u8>>)> = None;
+                let addresses = dmx::addresses(universe, start - 1, indices.len());
+                for (&led, (universe, channel)) in indices.iter().zip(addresses) {
+                    if channels.as_ref().map(|(current, _)| *current) != Some(universe) {
+                        channels = Some((universe, input.channels(universe)));
+                    }
+                    if let Some((_, Some(data))) = channels.as_ref() {
+                        let rgb = &data[channel..channel + 3];
+                        let color = Color::Rgb(
+                            rgb[0] as f32 / 255.0,
+                            rgb[1] as f32 / 255.0,
+                            rgb[2] as f32 / 255.0,
+                        );
+                        strip.colors[led] = strip.colors[led].blend(&color, op);
+                    }
+                }
+            }
+            Effect::Hardware(_) => {}
         }
     }
 }
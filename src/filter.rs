@@ -0,0 +1,144 @@
+use std::collections::VecDeque;
+use std::time::Instant;
+
+use serde::Deserialize;
+
+/// How the readings of a sensor are cleaned up before anything uses them.
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct SensorFilter {
+    #[serde(default)]
+    pub smoothing: Smoothing,
+    /// Readings outside of this range count as failed reads.
+    #[serde(default)]
+    pub range: Option<(f32, f32)>,
+    /// Readings that differ more than this from the last good one are rejected, until the new
+    /// value has held for `settle_time` seconds.
+    #[serde(default)]
+    pub max_jump: Option<f32>,
+    #[serde(default = "default_settle_time")]
+    pub settle_time: f32,
+    #[serde(default)]
+    pub fallback: Fallback,
+}
+
+#[derive(Deserialize, Clone, Debug, Default)]
+pub enum Smoothing {
+    #[default]
+    None,
+    /// Exponential moving average with this time constant in seconds.
+    Ema(f32),
+    /// Median of the readings from this many seconds.
+    Median(f32),
+}
+
+/// What a sensor reads while it has no good reading.
+#[derive(Deserialize, Clone, Debug, Default)]
+pub enum Fallback {
+    /// The sensor reads as missing, which everything using it has to handle.
+    #[default]
+    Missing,
+    /// Keep the last good value.
+    Hold,
+    Value(f32),
+}
+
+fn default_settle_time() -> f32 {
+    2.0
+}
+
+#[derive(Default)]
+pub struct FilterState {
+    filter: SensorFilter,
+    /// The last reading that was accepted, before smoothing.
+    last_good: Option<f32>,
+    output: Option<f32>,
+    last_time: Option<Instant>,
+    samples: VecDeque<(Instant, f32)>,
+    jump_since: Option<Instant>,
+    failed: bool,
+}
+
+impl FilterState {
+    pub fn new(filter: SensorFilter) -> Self {
+        Self {
+            filter,
+            ..Default::default()
+        }
+    }
+
+    pub fn apply(&mut self, name: &str, reading: Option<f32>, now: Instant) -> Option<f32> {
+        let value = match self.check(reading, now) {
+            Ok(value) => value,
+            Err(reason) => {
+                let fallback = match self.filter.fallback {
+                    Fallback::Missing => None,
+                    Fallback::Hold => self.output,
+                    Fallback::Value(value) => Some(value),
+                };
+                // sensors that never had a reading, like unplugged probes, aren't worth a warning
+                if !self.failed && self.last_good.is_some() {
+                    log::warn!("Sensor \"{}\" {}, using {:?}", name, reason, fallback);
+                }
+                self.failed = true;
+                return fallback;
+            }
+        };
+
+        if self.failed && self.last_good.is_some() {
+            log::info!("Sensor \"{}\" has recovered", name);
+        }
+        self.failed = false;
+        self.last_good = Some(value);
+
+        let output = match self.filter.smoothing {
+            Smoothing::None => value,
+            Smoothing::Ema(time_constant) => match (self.output, self.last_time) {
+                (Some(output), Some(last_time)) => {
+                    let dt = now.duration_since(last_time).as_secs_f32();
+                    let alpha = 1.0 - (-dt / time_constant.max(f32::EPSILON)).exp();
+                    output + (value - output) * alpha
+                }
+                _ => value,
+            },
+            Smoothing::Median(window) => {
+                self.samples.push_back((now, value));
+                while let Some(&(time, _)) = self.samples.front() {
+                    if now.duration_since(time).as_secs_f32() <= window || self.samples.len() == 1 {
+                        break;
+                    }
+                    self.samples.pop_front();
+                }
+                let mut sorted: Vec<f32> = self.samples.iter().map(|&(_, value)| value).collect();
+                sorted.sort_by(f32::total_cmp);
+                sorted[sorted.len() / 2]
+            }
+        };
+
+        self.output = Some(output);
+        self.last_time = Some(now);
+        Some(output)
+    }
+
+    /// Accept a reading, or give the reason it was rejected.
+    fn check(&mut self, reading: Option<f32>, now: Instant) -> Result<f32, &'static str> {
+        let value = reading.ok_or("has no reading")?;
+
+        if let Some((min, max)) = self.filter.range {
+            if value < min || value > max {
+                return Err("is out of range");
+            }
+        }
+
+        if let (Some(max_jump), Some(last_good)) = (self.filter.max_jump, self.last_good) {
+            if (value - last_good).abs() > max_jump {
+                let since = *self.jump_since.get_or_insert(now);
+                if now.duration_since(since).as_secs_f32() < self.filter.settle_time {
+                    return Err("jumped implausibly");
+                }
+            }
+        }
+        self.jump_since = None;
+
+        Ok(value)
+    }
+}
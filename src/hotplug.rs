@@ -0,0 +1,245 @@
+use std::cell::RefCell;
+use std::ffi::CString;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use anyhow::*;
+use hidapi::{DeviceInfo, HidApi};
+
+use crate::device::{Device, Fan, Shutdown, Strip};
+use crate::driver::Driver;
+use crate::transport::Unplugged;
+
+/// Update failures in a row before a device counts as unplugged.
+const MAX_FAILURES: usize = 3;
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Wraps the driver of a HID device so it can be unplugged and plugged back in while the daemon
+/// runs. While the device is gone it keeps the same name, fans, strips and probes, so everything
+/// that refers to it by position stays valid. The fan and strip settings made in the meantime are
+/// handed to the driver when the device comes back.
+///
+/// Devices are only looked for at startup. A controller that is plugged in for the first time
+/// later on is not picked up until the service restarts. A controller that fails to open at
+/// startup gets its fans, strips and probes from its driver, so profiles can refer to them before
+/// it comes back.
+pub struct HotplugDevice {
+    api: Rc<RefCell<HidApi>>,
+    driver: Driver,
+    /// Devices without a serial number are found by their HID path instead.
+    serial_number: Option<String>,
+    path: CString,
+    /// The serial number, or the HID path for devices without one.
+    identity: String,
+    device: Option<Box<dyn Device>>,
+    name: String,
+    probe_names: Vec<String>,
+    curve_probes: usize,
+    failures: usize,
+    last_attempt: Instant,
+    /// Stand-ins for the fans, strips and probes of the device while it is unplugged.
+    fans: Vec<Fan>,
+    strips: Vec<Strip>,
+    probes: Vec<Option<f32>>,
+}
+
+impl HotplugDevice {
+    /// A device that can't be opened starts out disconnected, and is tried again later.
+    pub fn open(api: &Rc<RefCell<HidApi>>, info: &DeviceInfo, driver: Driver) -> Self {
+        let serial_number = info
+            .serial_number()
+            .filter(|serial| !serial.is_empty())
+            .map(String::from);
+        let device = match info.open_device(&api.borrow()) {
+            Ok(device) => Some((driver.open)(Box::new(device))),
+            Err(e) => {
+                log::error!("Unable to open {}: {}", driver.name, e);
+                None
+            }
+        };
+
+        // the same with the device or without it, so positions never change
+        let mut layout = (driver.open)(Box::new(Unplugged));
+        Self {
+            api: Rc::clone(api),
+            driver,
+            identity: match serial_number.as_ref() {
+                Some(serial) => serial.clone(),
+                None => info.path().to_string_lossy().into_owned(),
+            },
+            serial_number,
+            path: info.path().to_owned(),
+            name: layout.name().to_string(),
+            probe_names: layout.probe_names(),
+            curve_probes: layout.curve_probes(),
+            device,
+            failures: 0,
+            last_attempt: Instant::now(),
+            fans: layout.fans().to_vec(),
+            strips: layout.strips().to_vec(),
+            probes: vec![None; layout.probes().len()],
+        }
+    }
+
+    fn disconnect(&mut self) {
+        if let Some(mut device) = self.device.take() {
+            log::error!(
+                "{} stopped responding, waiting for it to come back",
+                self.name
+            );
+            self.fans = device.fans().to_vec();
+            self.strips = device.strips().to_vec();
+            self.probes = vec![None; device.probes().len()];
+            self.last_attempt = Instant::now();
+        }
+    }
+
+    fn reconnect(&mut self) -> Result<()> {
+        let mut api = self.api.borrow_mut();
+        api.refresh_devices()?;
+
+        let info = api
+            .device_list()
+            .find(|info| {
+                self.driver.matches(info)
+                    && match self.serial_number.as_deref() {
+                        Some(serial) => info.serial_number() == Some(serial),
+                        None => info.path() == self.path.as_c_str(),
+                    }
+            })
+            .ok_or_else(|| anyhow!("{} is not plugged in", self.name))?;
+
+        let mut device = (self.driver.open)(Box::new(info.open_device(&api)?));
+        device.initialize()?;
+
+        // bring back what the profiles set up while the device was gone
+        for (fan, stand_in) in device.fans().iter_mut().zip(self.fans.iter()) {
+            *fan = stand_in.clone();
+        }
+        for (strip, stand_in) in device.strips().iter_mut().zip(self.strips.iter()) {
+            strip.colors = stand_in.colors.clone();
+            strip.hardware = stand_in.hardware.clone();
+        }
+
+        log::info!("{} is back", self.name);
+        self.device = Some(device);
+        self.failures = 0;
+        Ok(())
+    }
+}
+
+impl Device for HotplugDevice {
+    fn initialize(&mut self) -> Result<()> {
+        // a device that is gone gets initialized when it comes back
+        let device = match self.device.as_mut() {
+            Some(device) => device,
+            None => return Ok(()),
+        };
+        if let Err(e) = device.initialize() {
+            log::error!("Unable to initialize {}: {}", self.name, e);
+            self.disconnect();
+        }
+        Ok(())
+    }
+
+    fn is_led_only(&self) -> bool {
+        match self.device.as_ref() {
+            Some(device) => device.is_led_only(),
+            None => self.fans.is_empty() && self.probes.is_empty(),
+        }
+    }
+
+    fn name(&self) -> &str {
+        self.name.as_str()
+    }
+
+    fn identity(&self) -> Option<String> {
+        Some(self.identity.clone())
+    }
+
+    fn fans(&mut self) -> &mut [Fan] {
+        match self.device.as_mut() {
+            Some(device) => device.fans(),
+            None => &mut self.fans,
+        }
+    }
+
+    fn strips(&mut self) -> &mut [Strip] {
+        match self.device.as_mut() {
+            Some(device) => device.strips(),
+            None => &mut self.strips,
+        }
+    }
+
+    fn probes(&self) -> &[Option<f32>] {
+        match self.device.as_ref() {
+            Some(device) => device.probes(),
+            None => &self.probes,
+        }
+    }
+
+    fn probe_names(&self) -> Vec<String> {
+        self.probe_names.clone()
+    }
+
+    fn curve_probes(&self) -> usize {
+        self.curve_probes
+    }
+
+    fn rpms(&self) -> Vec<Option<u16>> {
+        match self.device.as_ref() {
+            Some(device) => device.rpms(),
+            None => vec![None; self.fans.len()],
+        }
+    }
+
+    fn stalled_fans(&self, samples: usize, min_duty: f32) -> Vec<usize> {
+        match self.device.as_ref() {
+            Some(device) => device.stalled_fans(samples, min_duty),
+            None => vec![],
+        }
+    }
+
+    fn report_status(&self) {
+        match self.device.as_ref() {
+            Some(device) => device.report_status(),
+            None => log::warn!("{} is disconnected", self.name),
+        }
+    }
+
+    fn update(&mut self) -> Result<()> {
+        let device = match self.device.as_mut() {
+            Some(device) => device,
+            None => {
+                if self.last_attempt.elapsed() >= RECONNECT_INTERVAL {
+                    self.last_attempt = Instant::now();
+                    if let Err(e) = self.reconnect() {
+                        log::debug!("Unable to reconnect: {}", e);
+                    }
+                }
+                return Ok(());
+            }
+        };
+
+        match device.update() {
+            Ok(()) => {
+                self.failures = 0;
+                Ok(())
+            }
+            Err(e) => {
+                self.failures += 1;
+                if self.failures >= MAX_FAILURES {
+                    self.disconnect();
+                }
+                Err(e)
+            }
+        }
+    }
+
+    fn shutdown(&mut self, shutdown: &Shutdown) -> Result<()> {
+        match self.device.as_mut() {
+            Some(device) => device.shutdown(shutdown),
+            None => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,472 @@
+use std::cell::Cell;
+use std::path::{Path, PathBuf};
+
+use anyhow::*;
+use serde::Deserialize;
+
+use crate::device::{curve_rpm, stalled, Device, Fan, Shutdown, StallCounter, Strip};
+
+/// A single chip exposed by the Linux hwmon subsystem, such as `k10temp`, `amdgpu` or `nct6798`.
+/// Its temperature inputs are published as probes and its `pwmN` outputs are exposed as fans.
+pub struct Hwmon {
+    name: String,
+    path: PathBuf,
+    temps: Vec<HwmonTemp>,
+    probes: Vec<Option<f32>>,
+    pwms: Vec<HwmonPwm>,
+    fans: Vec<Fan>,
+    rpms: Vec<Option<u16>>,
+    stalls: Vec<StallCounter>,
+}
+
+struct HwmonTemp {
+    input: PathBuf,
+    label: String,
+}
+
+struct HwmonPwm {
+    pwm: PathBuf,
+    enable: PathBuf,
+    fan_input: Option<PathBuf>,
+    /// The `pwmN_enable` value found at startup, restored when we let go of the header.
+    original_enable: Option<String>,
+    /// The fan setting that was last applied. Headers are left to the firmware until the fan
+    /// setting is changed for the first time.
+    applied: Fan,
+    controlled: bool,
+    duty: f32,
+    /// Rpm targets without an rpm readout are only warned about once.
+    warned: Cell<bool>,
+}
+
+const PWM_ENABLE_MANUAL: &str = "1";
+const RPM_CONTROL_GAIN: f32 = 0.1;
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct HwmonConfig {
+    #[serde(default = "default_root")]
+    pub root: String,
+    /// Only chips with one of these names are used. All chips are used when this is empty.
+    #[serde(default)]
+    pub chips: Vec<String>,
+}
+
+fn default_root() -> String {
+    String::from("/sys/class/hwmon")
+}
+
+/// Find all hwmon chips below the configured root, in the order of their hwmon index.
+pub fn discover(config: &HwmonConfig) -> Result<Vec<Hwmon>> {
+    let mut paths = Vec::new();
+    for entry in std::fs::read_dir(&config.root)
+        .with_context(|| format!("Unable to read hwmon root {}", config.root))?
+    {
+        let path = entry?.path();
+        if let Some(index) = numbered(&path, "hwmon", "") {
+            paths.push((index, path));
+        }
+    }
+    paths.sort();
+
+    let mut chips = Vec::new();
+    for (_, path) in paths {
+        // one odd chip shouldn't take the others down with it
+        let chip = match Hwmon::open(path) {
+            Ok(chip) => chip,
+            Err(e) => {
+                log::warn!("Skipping hwmon chip: {:?}", e);
+                continue;
+            }
+        };
+        if config.chips.is_empty() || config.chips.iter().any(|name| name == &chip.name) {
+            chips.push(chip);
+        }
+    }
+
+    Ok(chips)
+}
+
+/// Parse file names like `temp3_input` into their index.
+fn numbered(path: &Path, prefix: &str, suffix: &str) -> Option<usize> {
+    path.file_name()?
+        .to_str()?
+        .strip_prefix(prefix)?
+        .strip_suffix(suffix)?
+        .parse()
+        .ok()
+}
+
+fn read_trimmed(path: &Path) -> Option<String> {
+    std::fs::read_to_string(path)
+        .ok()
+        .map(|s| s.trim().to_string())
+}
+
+impl Hwmon {
+    pub fn open(path: PathBuf) -> Result<Self> {
+        let name = read_trimmed(&path.join("name"))
+            .ok_or_else(|| anyhow!("{} has no name", path.display()))?;
+
+        let mut temps = Vec::new();
+        for entry in std::fs::read_dir(&path)? {
+            let input = entry?.path();
+            if let Some(index) = numbered(&input, "temp", "_input") {
+                let label = read_trimmed(&path.join(format!("temp{}_label", index)))
+                    .unwrap_or_else(|| format!("temp{}", index));
+                temps.push((index, HwmonTemp { input, label }));
+            }
+        }
+        temps.sort_by_key(|&(index, _)| index);
+
+        let mut pwms = Vec::new();
+        for entry in std::fs::read_dir(&path)? {
+            let pwm = entry?.path();
+            if let Some(index) = numbered(&pwm, "pwm", "") {
+                let duty = read_trimmed(&pwm)
+                    .and_then(|value| value.parse::<u8>().ok())
+                    .map(|value| value as f32 / 255.0)
+                    .unwrap_or_default();
+                let fan_input = path.join(format!("fan{}_input", index));
+                let enable = path.join(format!("pwm{}_enable", index));
+                pwms.push((
+                    index,
+                    HwmonPwm {
+                        pwm,
+                        original_enable: read_trimmed(&enable),
+                        enable,
+                        fan_input: if fan_input.exists() {
+                            Some(fan_input)
+                        } else {
+                            None
+                        },
+                        applied: Fan::Pwm(duty),
+                        controlled: false,
+                        duty,
+                        warned: Cell::new(false),
+                    },
+                ));
+            }
+        }
+        pwms.sort_by_key(|&(index, _)| index);
+
+        Ok(Self {
+            name,
+            path,
+            probes: vec![None; temps.len()],
+            temps: temps.into_iter().map(|(_, temp)| temp).collect(),
+            fans: pwms.iter().map(|(_, pwm)| pwm.applied.clone()).collect(),
+            rpms: vec![None; pwms.len()],
+            stalls: vec![StallCounter::default(); pwms.len()],
+            pwms: pwms.into_iter().map(|(_, pwm)| pwm).collect(),
+        })
+    }
+
+    fn read_temp(temp: &HwmonTemp) -> Option<f32> {
+        read_trimmed(&temp.input)?
+            .parse::<i32>()
+            .ok()
+            .map(|millidegrees| millidegrees as f32 / 1000.0)
+    }
+
+    fn read_rpm(pwm: &HwmonPwm) -> Option<u16> {
+        read_trimmed(pwm.fan_input.as_ref()?)?.parse().ok()
+    }
+
+    /// Work out the duty cycle for a fan setting. Rpm targets are approached a little every update,
+    /// since hwmon only offers an rpm readout and no rpm target.
+    fn duty(&self, index: usize) -> f32 {
+        let pwm = &self.pwms[index];
+        let target = match &self.fans[index] {
+            &Fan::Pwm(duty) => return duty.clamp(0.0, 1.0),
+            &Fan::Rpm(rpm) => rpm as f32,
+            Fan::Curve(sensor, curve) => match self.probes.get(*sensor).cloned().flatten() {
+                Some(temp) => curve_rpm(curve, temp),
+                None => return 1.0,
+            },
+        };
+
+        match (self.rpms[index], target > 0.0) {
+            (_, false) => 0.0,
+            (Some(rpm), true) => {
+                let error = (target - rpm as f32) / target;
+                (pwm.duty + error * RPM_CONTROL_GAIN).clamp(0.0, 1.0)
+            }
+            (None, true) => {
+                if !pwm.warned.replace(true) {
+                    log::warn!(
+                        "{}: pwm{} has no rpm readout, running it at full speed",
+                        self.name,
+                        index + 1
+                    );
+                }
+                1.0
+            }
+        }
+    }
+
+    fn update_fans(&mut self) -> Result<()> {
+        for i in 0..self.pwms.len() {
+            if !self.pwms[i].controlled && self.fans[i] == self.pwms[i].applied {
+                continue;
+            }
+
+            let duty = self.duty(i);
+            let pwm = &mut self.pwms[i];
+            if !pwm.controlled {
+                std::fs::write(&pwm.enable, PWM_ENABLE_MANUAL)
+                    .with_context(|| format!("Unable to take control of {}", pwm.pwm.display()))?;
+                pwm.controlled = true;
+            }
+
+            let value = (duty * 255.0).round() as u8;
+            if value != (pwm.duty * 255.0).round() as u8 || pwm.applied != self.fans[i] {
+                std::fs::write(&pwm.pwm, value.to_string())
+                    .with_context(|| format!("Unable to write {}", pwm.pwm.display()))?;
+            }
+            pwm.duty = duty;
+            pwm.applied = self.fans[i].clone();
+        }
+
+        Ok(())
+    }
+
+    /// Give every header we took over back to the firmware.
+    fn release(&mut self) {
+        for pwm in self.pwms.iter_mut().filter(|pwm| pwm.controlled) {
+            if let Some(enable) = pwm.original_enable.as_ref() {
+                if let Err(e) = std::fs::write(&pwm.enable, enable) {
+                    log::error!("Unable to restore {}: {}", pwm.enable.display(), e);
+                }
+            }
+            pwm.controlled = false;
+        }
+    }
+}
+
+impl Drop for Hwmon {
+    fn drop(&mut self) {
+        self.release();
+    }
+}
+
+impl Device for Hwmon {
+    fn initialize(&mut self) -> Result<()> {
+        for (probe, temp) in self.probes.iter_mut().zip(self.temps.iter()) {
+            *probe = Self::read_temp(temp);
+        }
+
+        for (rpm, pwm) in self.rpms.iter_mut().zip(self.pwms.iter()) {
+            *rpm = Self::read_rpm(pwm);
+        }
+
+        log::info!(
+            "{} ({}): \n Temperature: {:?} \n Fan speeds: {:?}",
+            self.name,
+            self.path.display(),
+            self.temps
+                .iter()
+                .map(|temp| temp.label.as_str())
+                .zip(self.probes.iter())
+                .collect::<Vec<_>>(),
+            self.rpms
+        );
+
+        Ok(())
+    }
+
+    fn is_led_only(&self) -> bool {
+        self.probes.is_empty() && self.fans.is_empty()
+    }
+
+    fn name(&self) -> &str {
+        self.name.as_str()
+    }
+
+    fn fans(&mut self) -> &mut [Fan] {
+        &mut self.fans
+    }
+
+    fn strips(&mut self) -> &mut [Strip] {
+        &mut []
+    }
+
+    fn probes(&self) -> &[Option<f32>] {
+        &self.probes
+    }
+
+    fn probe_names(&self) -> Vec<String> {
+        self.temps.iter().map(|temp| temp.label.clone()).collect()
+    }
+
+    fn rpms(&self) -> Vec<Option<u16>> {
+        self.rpms.clone()
+    }
+
+    fn stalled_fans(&self, samples: usize, min_duty: f32) -> Vec<usize> {
+        stalled(&self.stalls, samples, min_duty)
+    }
+
+    fn report_status(&self) {
+        log::info!(
+            target: format!("{} status", self.name).as_str(),
+            "temperatures = {:?}, fan speeds = {:?}",
+            self.temps
+                .iter()
+                .map(|temp| temp.label.as_str())
+                .zip(self.probes.iter())
+                .collect::<Vec<_>>(),
+            self.rpms
+        )
+    }
+
+    fn update(&mut self) -> Result<()> {
+        for (probe, temp) in self.probes.iter_mut().zip(self.temps.iter()) {
+            *probe = Self::read_temp(temp);
+        }
+
+        for (rpm, pwm) in self.rpms.iter_mut().zip(self.pwms.iter()) {
+            *rpm = Self::read_rpm(pwm);
+        }
+
+        // headers that are left to the firmware may well have nothing plugged in
+        for i in 0..self.pwms.len() {
+            if let (true, Some(rpm)) = (self.pwms[i].controlled, self.rpms[i]) {
+                self.stalls[i].sample(&self.fans[i], rpm);
+            }
+        }
+
+        self.update_fans()
+    }
+
+    /// hwmon has no curves of its own, the firmware's automatic mode is the safe state.
+    fn shutdown(&mut self, _shutdown: &Shutdown) -> Result<()> {
+        self.release();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    /// A scratch hwmon root with a chip with a temperature probe, one without a name and one with
+    /// two pwm headers.
+    fn root(test: &str) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("fanservice-{}-{}", test, std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+
+        let k10temp = root.join("hwmon0");
+        fs::create_dir_all(&k10temp).unwrap();
+        fs::write(k10temp.join("name"), "k10temp\n").unwrap();
+        fs::write(k10temp.join("temp1_input"), "45250\n").unwrap();
+        fs::write(k10temp.join("temp1_label"), "Tctl\n").unwrap();
+
+        fs::create_dir_all(root.join("hwmon1")).unwrap();
+
+        let nct = root.join("hwmon10");
+        fs::create_dir_all(&nct).unwrap();
+        fs::write(nct.join("name"), "nct6798\n").unwrap();
+        fs::write(nct.join("temp3_input"), "30000\n").unwrap();
+        fs::write(nct.join("temp1_input"), "41000\n").unwrap();
+        fs::write(nct.join("pwm1"), "77\n").unwrap();
+        fs::write(nct.join("pwm1_enable"), "5\n").unwrap();
+        fs::write(nct.join("fan1_input"), "812\n").unwrap();
+        fs::write(nct.join("pwm2"), "255\n").unwrap();
+        fs::write(nct.join("pwm2_enable"), "2\n").unwrap();
+
+        fs::create_dir_all(root.join("power")).unwrap();
+        root
+    }
+
+    fn config(root: &Path, chips: &[&str]) -> HwmonConfig {
+        HwmonConfig {
+            root: root.display().to_string(),
+            chips: chips.iter().map(|chip| chip.to_string()).collect(),
+        }
+    }
+
+    fn read(path: PathBuf) -> String {
+        fs::read_to_string(path).unwrap().trim().to_string()
+    }
+
+    #[test]
+    fn discover_skips_broken_chips() {
+        let root = root("discover");
+
+        let chips = discover(&config(&root, &[])).unwrap();
+        let names: Vec<_> = chips.iter().map(|chip| chip.name.as_str()).collect();
+        assert_eq!(names, vec!["k10temp", "nct6798"]);
+
+        let chips = discover(&config(&root, &["nct6798"])).unwrap();
+        assert_eq!(chips.len(), 1);
+        assert_eq!(chips[0].probe_names(), vec!["temp1", "temp3"]);
+
+        drop(chips);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn reads_probes_and_rpms() {
+        let root = root("read");
+        let mut chips = discover(&config(&root, &[])).unwrap();
+
+        chips[0].initialize().unwrap();
+        assert_eq!(chips[0].probe_names(), vec!["Tctl"]);
+        assert_eq!(chips[0].probes(), &[Some(45.25)]);
+
+        chips[1].initialize().unwrap();
+        assert_eq!(chips[1].probes(), &[Some(41.0), Some(30.0)]);
+        assert_eq!(chips[1].rpms(), vec![Some(812), None]);
+
+        drop(chips);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn takes_over_and_releases_headers() {
+        let root = root("pwm");
+        let nct = root.join("hwmon10");
+        let mut chips = discover(&config(&root, &["nct6798"])).unwrap();
+        let chip = &mut chips[0];
+        chip.initialize().unwrap();
+
+        // headers are left alone until their fan setting changes
+        chip.update().unwrap();
+        assert_eq!(read(nct.join("pwm1_enable")), "5");
+        assert_eq!(read(nct.join("pwm1")), "77");
+
+        chip.fans()[0] = Fan::Pwm(0.5);
+        chip.update().unwrap();
+        assert_eq!(read(nct.join("pwm1_enable")), PWM_ENABLE_MANUAL);
+        assert_eq!(read(nct.join("pwm1")), "128");
+        assert_eq!(read(nct.join("pwm2_enable")), "2");
+        assert_eq!(read(nct.join("pwm2")), "255");
+
+        chip.shutdown(&Shutdown::default()).unwrap();
+        assert_eq!(read(nct.join("pwm1_enable")), "5");
+
+        drop(chips);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn rpm_target_without_readout() {
+        let root = root("readout");
+        let nct = root.join("hwmon10");
+        let mut chips = discover(&config(&root, &["nct6798"])).unwrap();
+        let chip = &mut chips[0];
+        chip.initialize().unwrap();
+
+        chip.fans()[1] = Fan::Rpm(1000);
+        chip.update().unwrap();
+        assert!(chip.pwms[1].warned.get());
+        chip.update().unwrap();
+        assert_eq!(read(nct.join("pwm2")), "255");
+        assert!(!chip.pwms[0].warned.get());
+
+        drop(chips);
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,127 @@
+use std::collections::HashMap;
+
+use anyhow::*;
+use serde::Deserialize;
+
+use crate::device::Device;
+
+/// A device referred to by name in the config. Besides its plain name, a device can be picked by
+/// its serial number or HID path, by its name and a number like `"Commander PRO#2"` when there are
+/// several of the same kind, or by a user alias. The numbers follow the order the devices are
+/// enumerated in, which isn't guaranteed to stay the same across reboots.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(from = "String")]
+pub struct DeviceRef {
+    pub name: String,
+    index: Option<usize>,
+}
+
+/// The names every device can be referred to by.
+pub struct DeviceNames {
+    /// The unique name of each device, used for display and as the prefix of its sensors.
+    display: Vec<String>,
+    /// Every name each device goes by.
+    names: Vec<Vec<String>>,
+    /// `None` marks a name that is ambiguous.
+    lookup: HashMap<String, Option<usize>>,
+}
+
+impl From<String> for DeviceRef {
+    fn from(name: String) -> Self {
+        Self { name, index: None }
+    }
+}
+
+impl DeviceRef {
+    /// Whether this refers to the device at `index` in the device list.
+    pub fn is(&self, index: usize) -> bool {
+        self.index == Some(index)
+    }
+}
+
+impl DeviceNames {
+    pub fn new(devices: &[Box<dyn Device>], aliases: &HashMap<String, String>) -> Result<Self> {
+        let mut table = Self {
+            display: Vec::new(),
+            names: Vec::new(),
+            lookup: HashMap::new(),
+        };
+
+        let mut counts: HashMap<&str, usize> = HashMap::new();
+        for device in devices.iter() {
+            *counts.entry(device.name()).or_default() += 1;
+        }
+
+        let mut numbers: HashMap<&str, usize> = HashMap::new();
+        for (index, device) in devices.iter().enumerate() {
+            let number = numbers.entry(device.name()).or_default();
+            *number += 1;
+            let numbered = format!("{}#{}", device.name(), number);
+
+            let mut names = vec![device.name().to_string(), numbered.clone()];
+            names.extend(device.identity());
+            for name in names.iter() {
+                table.insert(name.clone(), index);
+            }
+
+            table.display.push(if counts[device.name()] > 1 {
+                numbered
+            } else {
+                device.name().to_string()
+            });
+            table.names.push(names);
+        }
+
+        for (alias, name) in aliases.iter() {
+            let index = table
+                .lookup(name)
+                .with_context(|| format!("Invalid device alias \"{}\"", alias))?;
+            table.insert(alias.clone(), index);
+            table.names[index].push(alias.clone());
+        }
+
+        for (display, names) in table.display.iter().zip(table.names.iter()) {
+            let others: Vec<_> = names.iter().filter(|name| *name != display).collect();
+            log::info!("Device {}, also known as {:?}", display, others);
+        }
+
+        Ok(table)
+    }
+
+    fn insert(&mut self, name: String, index: usize) {
+        self.lookup
+            .entry(name)
+            .and_modify(|existing| {
+                if *existing != Some(index) {
+                    *existing = None
+                }
+            })
+            .or_insert(Some(index));
+    }
+
+    fn lookup(&self, name: &str) -> Result<usize> {
+        match self.lookup.get(name) {
+            Some(&Some(index)) => Ok(index),
+            Some(None) => bail!(
+                "Device name \"{}\" is ambiguous, use a serial number or a name like \"{}#1\"",
+                name,
+                name
+            ),
+            None => bail!("Unknown device \"{}\"", name),
+        }
+    }
+
+    pub fn resolve(&self, device: &mut DeviceRef) -> Result<()> {
+        device.index = Some(self.lookup(&device.name)?);
+        Ok(())
+    }
+
+    pub fn display(&self, index: usize) -> &str {
+        &self.display[index]
+    }
+
+    /// Every name the device at `index` goes by.
+    pub fn names(&self, index: usize) -> &[String] {
+        &self.names[index]
+    }
+}
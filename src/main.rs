@@ -1,20 +1,48 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 
 use env_logger::Target;
 use hidapi::*;
 use log::LevelFilter;
+use signal_hook::consts::{SIGINT, SIGTERM};
 
-use crate::corsair::CorsairLighting;
+use crate::adalight::Adalight;
 use crate::device::Device;
+use crate::driver::Registry;
+use crate::hotplug::HotplugDevice;
+use crate::openrgb_client::OpenRgbClient;
+use crate::profile::Config;
 use crate::profile_manager::ProfileManager;
+use crate::virtual_device::VirtualDevice;
+use crate::wled::Wled;
 use std::env::{current_exe, set_current_dir};
 
+mod adalight;
 mod color;
+mod commander_core;
 mod corsair;
+mod corsair_protocol;
 mod device;
+mod dmx;
+mod driver;
 mod effect;
+mod filter;
+mod hotplug;
+mod hwmon;
+mod identity;
+mod openrgb_client;
+mod openrgb_protocol;
+mod openrgb_server;
+mod pid;
 mod profile;
 mod profile_manager;
+mod sensor;
+mod transport;
+mod virtual_device;
+mod wled;
 
 fn main() {
     set_current_dir(current_exe().unwrap().parent().unwrap()).unwrap();
@@ -26,35 +54,85 @@ fn main() {
         .target(Target::Stdout)
         .init();
 
-    let config = ron::from_str(std::fs::read_to_string("config.ron").unwrap().as_str()).unwrap();
+    let registry = Registry::new();
+    let api = Rc::new(RefCell::new(HidApi::new().unwrap()));
 
-    let api = HidApi::new().unwrap();
+    if std::env::args().nth(1).as_deref() == Some("list-devices") {
+        registry.list_devices(&api.borrow());
+        return;
+    }
+
+    let config: Config =
+        ron::from_str(std::fs::read_to_string("config.ron").unwrap().as_str()).unwrap();
 
     let mut devices: Vec<Box<dyn Device>> = api
+        .borrow()
         .device_list()
-        .filter_map(|device| match device.vendor_id() {
-            0x1b1c => match device.product_id() {
-                0xc10 => Some(Box::new(CorsairLighting::new_commander_pro(
-                    device.open_device(&api).unwrap(),
-                )) as Box<_>),
-                0xc1a => Some(Box::new(CorsairLighting::new_lighting_node_core(
-                    device.open_device(&api).unwrap(),
-                )) as Box<_>),
-                _ => None,
-            },
-            _ => None,
+        .filter_map(|info| {
+            let driver = *registry.find(info)?;
+            Some(Box::new(HotplugDevice::open(&api, info, driver)) as Box<_>)
         })
         .collect();
 
+    devices.extend(
+        config
+            .virtual_devices
+            .iter()
+            .map(|config| Box::new(VirtualDevice::new(config.clone())) as Box<dyn Device>),
+    );
+
+    devices.extend(
+        config
+            .adalight
+            .iter()
+            .map(|config| Box::new(Adalight::new(config.clone())) as Box<dyn Device>),
+    );
+    devices.extend(
+        config
+            .wled
+            .iter()
+            .map(|config| Box::new(Wled::new(config.clone())) as Box<dyn Device>),
+    );
+    devices.extend(
+        config
+            .openrgb
+            .iter()
+            .map(|config| Box::new(OpenRgbClient::new(config.clone())) as Box<dyn Device>),
+    );
+
+    if let Some(hwmon) = config.hwmon.as_ref() {
+        match hwmon::discover(hwmon) {
+            Ok(chips) => devices.extend(chips.into_iter().map(|chip| Box::new(chip) as Box<_>)),
+            Err(e) => log::error!("Unable to discover hwmon sensors: {}", e),
+        }
+    }
+
     for device in devices.iter_mut() {
-        device.initialize().unwrap();
+        if let Err(e) = device.initialize() {
+            log::error!("Unable to initialize {}: {}", device.name(), e);
+        }
         std::thread::sleep(Duration::from_millis(50));
     }
 
-    let mut profile_manager = ProfileManager::new(devices, config);
+    let mut profile_manager = match ProfileManager::new(devices, config) {
+        Ok(profile_manager) => profile_manager,
+        Err(e) => {
+            log::error!("Invalid config: {:?}", e);
+            std::process::exit(1);
+        }
+    };
+
+    // the first signal stops the loop so the profile manager can restore the hardware,
+    // a second one exits right away in case that gets stuck
+    let terminate = Arc::new(AtomicBool::new(false));
+    for &signal in [SIGINT, SIGTERM].iter() {
+        signal_hook::flag::register_conditional_shutdown(signal, 1, Arc::clone(&terminate))
+            .unwrap();
+        signal_hook::flag::register(signal, Arc::clone(&terminate)).unwrap();
+    }
 
     let mut deadline = Instant::now() + Duration::from_millis(30);
-    loop {
+    while !terminate.load(Ordering::Relaxed) {
         profile_manager.update();
         let now = Instant::now();
         if now < deadline {
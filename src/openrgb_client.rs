@@ -0,0 +1,505 @@
+use std::io::ErrorKind;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::mpsc::{channel, Receiver, TryRecvError};
+use std::time::{Duration, Instant};
+
+use anyhow::*;
+use serde::Deserialize;
+
+use crate::color::Color;
+use crate::device::{Device, Fan, HardwareEffect, Shutdown, Strip};
+use crate::openrgb_protocol::*;
+
+/// The lighting of a running OpenRGB server, such as the motherboard, memory and graphics card.
+/// Every zone of every controller on the server is a strip, in the order the server lists them.
+pub struct OpenRgbClient {
+    config: OpenRgbClientConfig,
+    stream: Option<TcpStream>,
+    version: u32,
+    zones: Vec<RemoteZone>,
+    strips: Vec<Strip>,
+    strips_dirty: bool,
+    last_attempt: Option<Instant>,
+    /// The result of the connection attempt that is under way.
+    connecting: Option<Receiver<Result<Connection>>>,
+    /// The idle effects, for zones that the server only lists once we get to connect.
+    idle: Option<Shutdown>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct OpenRgbClientConfig {
+    #[serde(default = "default_name")]
+    pub name: String,
+    /// A host name or ip address, with an optional port.
+    #[serde(default = "default_address")]
+    pub address: String,
+}
+
+/// A connection that made it through the handshake, and the zones the server listed.
+struct Connection {
+    stream: TcpStream,
+    version: u32,
+    zones: Vec<RemoteZone>,
+}
+
+struct RemoteZone {
+    controller: u32,
+    zone: u32,
+    leds: usize,
+}
+
+const CLIENT_NAME: &str = "fanservice";
+const TIMEOUT: Duration = Duration::from_secs(2);
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(5);
+
+fn default_name() -> String {
+    "OpenRGB".to_string()
+}
+
+fn default_address() -> String {
+    format!("127.0.0.1:{}", DEFAULT_PORT)
+}
+
+fn request(stream: &mut TcpStream, device: u32, id: u32, data: &[u8]) -> Result<Vec<u8>> {
+    write_packet(stream, device, id, data)?;
+    loop {
+        let packet = read_packet(stream)?;
+        // the server may tell us about new devices in between
+        if packet.id == id {
+            return Ok(packet.data);
+        }
+    }
+}
+
+/// Connects to the server and lists its zones. This can take a while when the server is down,
+/// so once we are running it happens on a thread of its own.
+fn connect(config: &OpenRgbClientConfig) -> Result<Connection> {
+    let address = match config.address.to_socket_addrs() {
+        Ok(mut addresses) => addresses.next(),
+        // no port was given
+        Err(_) => (config.address.as_str(), DEFAULT_PORT)
+            .to_socket_addrs()?
+            .next(),
+    }
+    .ok_or_else(|| anyhow!("Unable to resolve {}", config.address))?;
+
+    let mut stream = TcpStream::connect_timeout(&address, TIMEOUT)
+        .with_context(|| format!("Unable to connect to {}", config.address))?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    stream.set_nodelay(true)?;
+
+    let mut name = CLIENT_NAME.as_bytes().to_vec();
+    name.push(0);
+    write_packet(&mut stream, 0, SET_CLIENT_NAME, &name)?;
+
+    // servers that predate the version request never answer it
+    let ours = PROTOCOL_VERSION.to_le_bytes();
+    let version = match request(&mut stream, 0, REQUEST_PROTOCOL_VERSION, &ours) {
+        Ok(data) => Decoder::new(&data).u32()?.min(PROTOCOL_VERSION),
+        Err(_) => 0,
+    };
+
+    // the first protocol version sends no version with the controller data request
+    let requested = match version {
+        0 => vec![],
+        version => version.to_le_bytes().to_vec(),
+    };
+
+    let count = Decoder::new(&request(&mut stream, 0, REQUEST_CONTROLLER_COUNT, &[])?).u32()?;
+    let mut zones = Vec::new();
+    for controller in 0..count {
+        let data = request(&mut stream, controller, REQUEST_CONTROLLER_DATA, &requested)?;
+        let data = Controller::decode(&data, version)
+            .with_context(|| format!("Invalid data for OpenRGB controller {}", controller))?;
+
+        // take over the leds from whatever mode the controller was in
+        write_packet(&mut stream, controller, RGBCONTROLLER_SETCUSTOMMODE, &[])?;
+
+        for (zone, info) in data.zones.iter().enumerate() {
+            log::info!(
+                "{} channel {} is {} / {} with {} leds",
+                config.name,
+                zones.len(),
+                data.name,
+                info.name,
+                info.leds
+            );
+            zones.push(RemoteZone {
+                controller,
+                zone: zone as u32,
+                leds: info.leds,
+            });
+        }
+    }
+
+    Ok(Connection {
+        stream,
+        version,
+        zones,
+    })
+}
+
+impl OpenRgbClient {
+    pub fn new(config: OpenRgbClientConfig) -> Self {
+        Self {
+            config,
+            stream: None,
+            version: 0,
+            zones: vec![],
+            strips: vec![],
+            strips_dirty: true,
+            last_attempt: None,
+            connecting: None,
+            idle: None,
+        }
+    }
+
+    /// Takes over the zones of a new connection. Strips are kept for zones that were there
+    /// before, so a restart of the server doesn't lose the colors.
+    fn adopt(&mut self, connection: Connection) {
+        let known = self.strips.len();
+        self.strips.resize(connection.zones.len(), Strip::default());
+        if let Some(idle) = self.idle.as_ref() {
+            for (channel, strip) in self.strips.iter_mut().enumerate().skip(known) {
+                strip.hardware = Some(idle.effect(channel).clone());
+            }
+        }
+        for (strip, zone) in self.strips.iter_mut().zip(connection.zones.iter()) {
+            strip.leds = Some(zone.leds);
+        }
+
+        self.stream = Some(connection.stream);
+        self.version = connection.version;
+        self.zones = connection.zones;
+        self.strips_dirty = true;
+    }
+
+    /// The server sends nothing on its own, except when its device list changes.
+    fn device_list_updated(&mut self) -> Result<bool> {
+        let stream = self.stream.as_mut().unwrap();
+        stream.set_nonblocking(true)?;
+        let pending = stream.peek(&mut [0u8; 1]);
+        stream.set_nonblocking(false)?;
+        match pending {
+            Ok(0) => bail!("{} closed the connection", self.config.address),
+            Ok(_) => Ok(read_packet(stream)?.id == DEVICE_LIST_UPDATED),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn send(&mut self, index: usize, colors: &[Color]) -> Result<()> {
+        let zone = &self.zones[index];
+        let mut encoder = Encoder::default();
+        encoder.u32(zone.zone).colors(colors);
+
+        // the size of the data comes first, including itself
+        let mut data = ((encoder.data.len() + 4) as u32).to_le_bytes().to_vec();
+        data.extend(encoder.data);
+
+        let controller = zone.controller;
+        let stream = self.stream.as_mut().unwrap();
+        write_packet(stream, controller, RGBCONTROLLER_UPDATEZONELEDS, &data)
+    }
+
+    fn send_all(&mut self, strips: &[Strip]) -> Result<()> {
+        for (index, strip) in strips.iter().enumerate() {
+            // hardware effects are left to the modes the server already has
+            match strip.hardware {
+                None | Some(HardwareEffect::Static(_)) => {}
+                Some(_) => continue,
+            }
+            let colors = strip.render(self.zones[index].leds);
+            if !colors.is_empty() {
+                self.send(index, &colors)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Device for OpenRgbClient {
+    /// OpenRGB may well start after us, so a server that isn't there yet is tried again later.
+    fn initialize(&mut self) -> Result<()> {
+        self.stream = None;
+        self.connecting = None;
+        self.last_attempt = Some(Instant::now());
+        match connect(&self.config) {
+            Ok(connection) => self.adopt(connection),
+            Err(e) => log::warn!("{}: {}", self.config.name, e),
+        }
+        Ok(())
+    }
+
+    fn is_led_only(&self) -> bool {
+        true
+    }
+
+    fn name(&self) -> &str {
+        self.config.name.as_str()
+    }
+
+    fn fans(&mut self) -> &mut [Fan] {
+        &mut []
+    }
+
+    fn strips(&mut self) -> &mut [Strip] {
+        self.strips_dirty = true;
+        &mut self.strips
+    }
+
+    fn probes(&self) -> &[Option<f32>] {
+        &[]
+    }
+
+    fn report_status(&self) {}
+
+    fn idle(&mut self, shutdown: &Shutdown) {
+        for (channel, strip) in self.strips.iter_mut().enumerate() {
+            strip.hardware = Some(shutdown.effect(channel).clone());
+        }
+        self.idle = Some(shutdown.clone());
+        self.strips_dirty = true;
+    }
+
+    fn update(&mut self) -> Result<()> {
+        if self.stream.is_some() {
+            match self.device_list_updated() {
+                Ok(false) => {}
+                Ok(true) => {
+                    log::info!("The devices on {} changed", self.config.address);
+                    self.stream = None;
+                    self.last_attempt = None;
+                }
+                Err(e) => {
+                    self.stream = None;
+                    return Err(e);
+                }
+            }
+        }
+
+        if self.stream.is_none() {
+            let connecting = match self.connecting.as_ref() {
+                Some(connecting) => connecting,
+                None => {
+                    if self
+                        .last_attempt
+                        .is_some_and(|last| last.elapsed() < RECONNECT_INTERVAL)
+                    {
+                        return Ok(());
+                    }
+                    // the control loop doesn't wait for the server
+                    self.last_attempt = Some(Instant::now());
+                    let (sender, receiver) = channel();
+                    let config = self.config.clone();
+                    std::thread::spawn(move || sender.send(connect(&config)));
+                    self.connecting = Some(receiver);
+                    return Ok(());
+                }
+            };
+            match connecting.try_recv() {
+                Ok(Ok(connection)) => {
+                    self.connecting = None;
+                    self.adopt(connection);
+                }
+                Ok(Err(e)) => {
+                    self.connecting = None;
+                    return Err(e);
+                }
+                Err(TryRecvError::Empty) => return Ok(()),
+                Err(TryRecvError::Disconnected) => {
+                    self.connecting = None;
+                    return Ok(());
+                }
+            }
+        }
+
+        if !self.strips_dirty {
+            return Ok(());
+        }
+
+        let strips = std::mem::take(&mut self.strips);
+        let result = self.send_all(&strips);
+        self.strips = strips;
+        if let Err(e) = result {
+            // connect again later, in case the server restarted
+            self.stream = None;
+            return Err(e).with_context(|| format!("Unable to update {}", self.config.address));
+        }
+
+        self.strips_dirty = false;
+        Ok(())
+    }
+
+    /// The remote leds keep the last colors they were sent, unless the strip should be left on a
+    /// static color.
+    fn shutdown(&mut self, shutdown: &Shutdown) -> Result<()> {
+        if self.stream.is_none() {
+            return Ok(());
+        }
+        for index in 0..self.zones.len() {
+            if let HardwareEffect::Static(color) = shutdown.effect(index) {
+                let colors = vec![*color; self.zones[index].leds];
+                self.send(index, &colors)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+    use std::sync::mpsc::{channel, Receiver};
+
+    use super::*;
+
+    /// A server with the given controllers, that answers the version request if it knows it.
+    /// Every packet it receives is passed on.
+    fn server(controllers: Vec<Controller>, version: Option<u32>) -> (String, Receiver<Packet>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let (sender, receiver) = channel();
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            while let Ok(packet) = read_packet(&mut stream) {
+                let device = packet.device;
+                let reply = match packet.id {
+                    REQUEST_PROTOCOL_VERSION => {
+                        version.map(|version| version.to_le_bytes().to_vec())
+                    }
+                    REQUEST_CONTROLLER_COUNT => {
+                        Some((controllers.len() as u32).to_le_bytes().to_vec())
+                    }
+                    REQUEST_CONTROLLER_DATA => {
+                        let version = Decoder::new(&packet.data).u32().unwrap_or(0);
+                        Some(controllers[device as usize].encode(version))
+                    }
+                    _ => None,
+                };
+                let id = packet.id;
+                sender.send(packet).unwrap();
+                if let Some(reply) = reply {
+                    write_packet(&mut stream, device, id, &reply).unwrap();
+                }
+            }
+        });
+        (address, receiver)
+    }
+
+    fn controller(zones: &[usize]) -> Controller {
+        Controller {
+            name: "Controller".to_string(),
+            vendor: "Vendor".to_string(),
+            zones: zones
+                .iter()
+                .map(|&leds| Zone {
+                    name: "Zone".to_string(),
+                    zone_type: ZONE_TYPE_LINEAR,
+                    leds,
+                })
+                .collect(),
+            ..Controller::default()
+        }
+    }
+
+    fn client(address: String) -> OpenRgbClient {
+        OpenRgbClient::new(OpenRgbClientConfig {
+            name: "Test".to_string(),
+            address,
+        })
+    }
+
+    fn received(receiver: &Receiver<Packet>, id: u32) -> Vec<Packet> {
+        receiver
+            .try_iter()
+            .filter(|packet| packet.id == id)
+            .collect()
+    }
+
+    #[test]
+    fn connect_lists_every_zone() {
+        let (address, receiver) = server(vec![controller(&[3]), controller(&[2, 1])], Some(3));
+        let mut client = client(address);
+        client.initialize().unwrap();
+
+        assert_eq!(client.version, 3);
+        let leds: Vec<_> = client.strips().iter().map(|strip| strip.leds).collect();
+        assert_eq!(leds, vec![Some(3), Some(2), Some(1)]);
+
+        let requests = received(&receiver, REQUEST_CONTROLLER_DATA);
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[1].device, 1);
+        assert_eq!(requests[1].data, 3u32.to_le_bytes());
+    }
+
+    #[test]
+    fn old_servers_get_the_first_version() {
+        let (address, receiver) = server(vec![controller(&[4])], None);
+        let mut client = client(address);
+        client.initialize().unwrap();
+
+        assert_eq!(client.version, 0);
+        assert_eq!(client.strips()[0].leds, Some(4));
+        let requests = received(&receiver, REQUEST_CONTROLLER_DATA);
+        assert!(requests[0].data.is_empty());
+    }
+
+    #[test]
+    fn update_sends_zone_leds() {
+        let (address, receiver) = server(vec![controller(&[3]), controller(&[2, 1])], Some(3));
+        let mut client = client(address);
+        client.initialize().unwrap();
+        client.strips()[2].colors = vec![Color::Rgb(1.0, 0.0, 0.0)];
+        client.update().unwrap();
+
+        // the packets arrive on the other thread
+        let mut updates = vec![];
+        while updates.len() < 3 {
+            let packet = receiver.recv_timeout(TIMEOUT).unwrap();
+            if packet.id == RGBCONTROLLER_UPDATEZONELEDS {
+                updates.push(packet);
+            }
+        }
+        assert_eq!(updates[2].device, 1);
+        assert_eq!(
+            updates[2].data,
+            vec![14, 0, 0, 0, 1, 0, 0, 0, 1, 0, 255, 0, 0, 0]
+        );
+    }
+
+    #[test]
+    fn update_connects_in_the_background() {
+        // the server takes the whole timeout to not answer the version request
+        let (address, _receiver) = server(vec![controller(&[3])], None);
+        let mut client = client(address);
+
+        let started = Instant::now();
+        client.update().unwrap();
+        assert!(started.elapsed() < TIMEOUT / 4);
+        assert!(client.strips().is_empty());
+
+        while client.strips().is_empty() {
+            assert!(started.elapsed() < TIMEOUT * 2);
+            std::thread::sleep(Duration::from_millis(10));
+            client.update().unwrap();
+        }
+        assert_eq!(client.strips()[0].leds, Some(3));
+    }
+
+    #[test]
+    fn late_zones_show_the_idle_effect() {
+        let (address, _receiver) = server(vec![controller(&[3, 2])], Some(3));
+        let mut client = client(address);
+        let mut shutdown: Shutdown = ron::from_str("()").unwrap();
+        shutdown.effect = HardwareEffect::Static(Color::Rgb(0.0, 0.0, 1.0));
+
+        // the server is only reached after the profiles set up the idle effects
+        client.idle(&shutdown);
+        client.initialize().unwrap();
+        for strip in client.strips().iter() {
+            assert_eq!(strip.hardware, Some(shutdown.effect.clone()));
+        }
+    }
+}
@@ -0,0 +1,430 @@
+use std::io::{Read, Write};
+
+use anyhow::*;
+
+use crate::color::Color;
+
+/// The highest version of the OpenRGB SDK protocol that we speak. Version 1 added the vendor
+/// string to the controller data, version 2 the profile packets and version 3 the mode
+/// brightness.
+pub const PROTOCOL_VERSION: u32 = 3;
+
+pub const DEFAULT_PORT: u16 = 6742;
+
+const MAGIC: &[u8; 4] = b"ORGB";
+const HEADER_LENGTH: usize = 16;
+/// Far more than any controller list, but keeps a confused peer from allocating gigabytes.
+const MAX_PACKET_LENGTH: usize = 16 * 1024 * 1024;
+
+pub const REQUEST_CONTROLLER_COUNT: u32 = 0;
+pub const REQUEST_CONTROLLER_DATA: u32 = 1;
+pub const REQUEST_PROTOCOL_VERSION: u32 = 40;
+pub const SET_CLIENT_NAME: u32 = 50;
+pub const DEVICE_LIST_UPDATED: u32 = 100;
+pub const REQUEST_PROFILE_LIST: u32 = 150;
+pub const RGBCONTROLLER_RESIZEZONE: u32 = 1000;
+pub const RGBCONTROLLER_UPDATELEDS: u32 = 1050;
+pub const RGBCONTROLLER_UPDATEZONELEDS: u32 = 1051;
+pub const RGBCONTROLLER_UPDATESINGLELED: u32 = 1052;
+pub const RGBCONTROLLER_SETCUSTOMMODE: u32 = 1100;
+pub const RGBCONTROLLER_UPDATEMODE: u32 = 1101;
+pub const RGBCONTROLLER_SAVEMODE: u32 = 1102;
+
+pub const DEVICE_TYPE_COOLER: i32 = 3;
+pub const DEVICE_TYPE_LEDSTRIP: i32 = 4;
+
+const MODE_FLAG_HAS_PER_LED_COLOR: u32 = 1 << 5;
+const MODE_COLORS_PER_LED: u32 = 1;
+
+pub const ZONE_TYPE_LINEAR: i32 = 1;
+
+/// A packet of the OpenRGB SDK protocol. `device` is the controller a request is about.
+pub struct Packet {
+    pub device: u32,
+    pub id: u32,
+    pub data: Vec<u8>,
+}
+
+/// A controller as described in the controller data packet, limited to what a bunch of led
+/// strips needs. Every controller has a single "Direct" mode that sets the leds one by one.
+#[derive(Clone, Debug, Default)]
+pub struct Controller {
+    pub device_type: i32,
+    pub name: String,
+    pub vendor: String,
+    pub description: String,
+    pub version: String,
+    pub serial: String,
+    pub location: String,
+    pub zones: Vec<Zone>,
+    pub colors: Vec<Color>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Zone {
+    pub name: String,
+    pub zone_type: i32,
+    pub leds: usize,
+}
+
+pub fn read_packet(stream: &mut impl Read) -> Result<Packet> {
+    let mut header = [0u8; HEADER_LENGTH];
+    stream.read_exact(&mut header)?;
+    if &header[..4] != MAGIC {
+        bail!("Not an OpenRGB packet");
+    }
+
+    let mut decoder = Decoder::new(&header[4..]);
+    let device = decoder.u32()?;
+    let id = decoder.u32()?;
+    let length = decoder.u32()? as usize;
+    if length > MAX_PACKET_LENGTH {
+        bail!("OpenRGB packet of {} bytes is too long", length);
+    }
+
+    let mut data = vec![0u8; length];
+    stream.read_exact(&mut data)?;
+    Ok(Packet { device, id, data })
+}
+
+pub fn write_packet(stream: &mut impl Write, device: u32, id: u32, data: &[u8]) -> Result<()> {
+    let mut packet = Vec::with_capacity(HEADER_LENGTH + data.len());
+    packet.extend_from_slice(MAGIC);
+    packet.extend_from_slice(&device.to_le_bytes());
+    packet.extend_from_slice(&id.to_le_bytes());
+    packet.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    packet.extend_from_slice(data);
+    stream.write_all(&packet)?;
+    Ok(())
+}
+
+/// Colors go over the wire as red, green and blue bytes, padded to four.
+pub fn encode_color(color: &Color) -> [u8; 4] {
+    let [r, g, b] = color.rgb();
+    [(r * 255.0) as u8, (g * 255.0) as u8, (b * 255.0) as u8, 0]
+}
+
+pub fn decode_color(bytes: [u8; 4]) -> Color {
+    Color::Rgb(
+        bytes[0] as f32 / 255.0,
+        bytes[1] as f32 / 255.0,
+        bytes[2] as f32 / 255.0,
+    )
+}
+
+/// Builds the little endian fields the protocol is made of.
+#[derive(Default)]
+pub struct Encoder {
+    pub data: Vec<u8>,
+}
+
+impl Encoder {
+    pub fn u16(&mut self, value: u16) -> &mut Self {
+        self.data.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    pub fn u32(&mut self, value: u32) -> &mut Self {
+        self.data.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    pub fn i32(&mut self, value: i32) -> &mut Self {
+        self.data.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    /// Strings carry their length, including the terminating zero.
+    pub fn string(&mut self, value: &str) -> &mut Self {
+        self.u16(value.len() as u16 + 1);
+        self.data.extend_from_slice(value.as_bytes());
+        self.data.push(0);
+        self
+    }
+
+    pub fn colors(&mut self, colors: &[Color]) -> &mut Self {
+        self.u16(colors.len() as u16);
+        for color in colors.iter() {
+            self.data.extend_from_slice(&encode_color(color));
+        }
+        self
+    }
+}
+
+/// Reads the fields of a packet, failing on packets that are cut short.
+pub struct Decoder<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Decoder<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+
+    fn take(&mut self, length: usize) -> Result<&'a [u8]> {
+        if self.data.len() < length {
+            bail!("OpenRGB packet is too short");
+        }
+        let (taken, rest) = self.data.split_at(length);
+        self.data = rest;
+        Ok(taken)
+    }
+
+    pub fn u16(&mut self) -> Result<u16> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    pub fn u32(&mut self) -> Result<u32> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    pub fn i32(&mut self) -> Result<i32> {
+        Ok(self.u32()? as i32)
+    }
+
+    pub fn string(&mut self) -> Result<String> {
+        let length = self.u16()? as usize;
+        let bytes = self.take(length)?;
+        let bytes = bytes.strip_suffix(&[0]).unwrap_or(bytes);
+        Ok(String::from_utf8_lossy(bytes).into_owned())
+    }
+
+    pub fn color(&mut self) -> Result<Color> {
+        let bytes = self.take(4)?;
+        Ok(decode_color([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    pub fn colors(&mut self) -> Result<Vec<Color>> {
+        let count = self.u16()?;
+        (0..count).map(|_| self.color()).collect()
+    }
+}
+
+impl Controller {
+    pub fn leds(&self) -> usize {
+        self.zones.iter().map(|zone| zone.leds).sum()
+    }
+
+    /// Reads a controller data packet of protocol `version`. Modes and led names are skipped.
+    pub fn decode(data: &[u8], version: u32) -> Result<Self> {
+        let mut decoder = Decoder::new(data);
+        let _size = decoder.u32()?;
+        let device_type = decoder.i32()?;
+        let name = decoder.string()?;
+        let vendor = if version >= 1 {
+            decoder.string()?
+        } else {
+            String::new()
+        };
+        let description = decoder.string()?;
+        let controller_version = decoder.string()?;
+        let serial = decoder.string()?;
+        let location = decoder.string()?;
+
+        let modes = decoder.u16()?;
+        let _active_mode = decoder.i32()?;
+        for _ in 0..modes {
+            decoder.string()?;
+            // value, flags, speeds, colors, speed, direction and color mode
+            let fields = if version >= 3 { 12 } else { 9 };
+            decoder.take(fields * 4)?;
+            decoder.colors()?;
+        }
+
+        let mut zones = Vec::new();
+        for _ in 0..decoder.u16()? {
+            let name = decoder.string()?;
+            let zone_type = decoder.i32()?;
+            let _leds_min = decoder.u32()?;
+            let _leds_max = decoder.u32()?;
+            let leds = decoder.u32()? as usize;
+            let matrix = decoder.u16()? as usize;
+            decoder.take(matrix)?;
+            zones.push(Zone {
+                name,
+                zone_type,
+                leds,
+            });
+        }
+
+        for _ in 0..decoder.u16()? {
+            decoder.string()?;
+            decoder.u32()?;
+        }
+        let colors = decoder.colors()?;
+
+        Ok(Self {
+            device_type,
+            name,
+            vendor,
+            description,
+            version: controller_version,
+            serial,
+            location,
+            zones,
+            colors,
+        })
+    }
+
+    /// The controller data packet, as understood by clients of protocol `version`.
+    pub fn encode(&self, version: u32) -> Vec<u8> {
+        let mut encoder = Encoder::default();
+        encoder.i32(self.device_type).string(&self.name);
+        if version >= 1 {
+            encoder.string(&self.vendor);
+        }
+        encoder
+            .string(&self.description)
+            .string(&self.version)
+            .string(&self.serial)
+            .string(&self.location);
+
+        // the modes, and the one that is active
+        encoder.u16(1).i32(0);
+        encoder
+            .string("Direct")
+            .i32(0)
+            .u32(MODE_FLAG_HAS_PER_LED_COLOR)
+            .u32(0)
+            .u32(0);
+        if version >= 3 {
+            encoder.u32(0).u32(0);
+        }
+        encoder.u32(0).u32(0).u32(0);
+        if version >= 3 {
+            encoder.u32(0);
+        }
+        encoder.u32(0).u32(MODE_COLORS_PER_LED).u16(0);
+
+        encoder.u16(self.zones.len() as u16);
+        for zone in self.zones.iter() {
+            let leds = zone.leds as u32;
+            encoder
+                .string(&zone.name)
+                .i32(zone.zone_type)
+                .u32(leds)
+                .u32(leds)
+                .u32(leds)
+                // no matrix map
+                .u16(0);
+        }
+
+        encoder.u16(self.leds() as u16);
+        for zone in self.zones.iter() {
+            for led in 0..zone.leds {
+                encoder
+                    .string(&format!("{} LED {}", zone.name, led + 1))
+                    .u32(0);
+            }
+        }
+
+        let mut colors = self.colors.clone();
+        colors.resize(self.leds(), Color::Rgb(0.0, 0.0, 0.0));
+        encoder.colors(&colors);
+
+        // the size of the whole packet comes first
+        let mut data = ((encoder.data.len() + 4) as u32).to_le_bytes().to_vec();
+        data.extend(encoder.data);
+        data
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn controller() -> Controller {
+        Controller {
+            device_type: DEVICE_TYPE_LEDSTRIP,
+            name: "Strip".to_string(),
+            vendor: "Vendor".to_string(),
+            description: "fanservice".to_string(),
+            version: "1.0".to_string(),
+            serial: "1234".to_string(),
+            location: "Here".to_string(),
+            zones: vec![
+                Zone {
+                    name: "Channel 1".to_string(),
+                    zone_type: ZONE_TYPE_LINEAR,
+                    leds: 2,
+                },
+                Zone {
+                    name: "Channel 2".to_string(),
+                    zone_type: ZONE_TYPE_LINEAR,
+                    leds: 1,
+                },
+            ],
+            colors: vec![
+                Color::Rgb(1.0, 0.0, 0.0),
+                Color::Rgb(0.0, 1.0, 0.0),
+                Color::Rgb(0.0, 0.0, 1.0),
+            ],
+        }
+    }
+
+    #[test]
+    fn controller_round_trip() {
+        let controller = controller();
+        for version in 0..=PROTOCOL_VERSION {
+            let data = controller.encode(version);
+            assert_eq!(
+                u32::from_le_bytes([data[0], data[1], data[2], data[3]]) as usize,
+                data.len()
+            );
+
+            let decoded = Controller::decode(&data, version).unwrap();
+            assert_eq!(decoded.device_type, controller.device_type);
+            assert_eq!(decoded.name, controller.name);
+            // the first version has no vendor
+            let vendor = if version == 0 { "" } else { "Vendor" };
+            assert_eq!(decoded.vendor, vendor);
+            assert_eq!(decoded.description, controller.description);
+            assert_eq!(decoded.version, controller.version);
+            assert_eq!(decoded.serial, controller.serial);
+            assert_eq!(decoded.location, controller.location);
+
+            let zones: Vec<_> = decoded.zones.iter().map(|zone| zone.leds).collect();
+            assert_eq!(zones, vec![2, 1]);
+            assert_eq!(decoded.zones[1].name, "Channel 2");
+            assert_eq!(decoded.zones[1].zone_type, ZONE_TYPE_LINEAR);
+            let colors: Vec<_> = decoded.colors.iter().map(encode_color).collect();
+            assert_eq!(colors, vec![[255, 0, 0, 0], [0, 255, 0, 0], [0, 0, 255, 0]]);
+        }
+    }
+
+    #[test]
+    fn controller_cut_short() {
+        let data = controller().encode(PROTOCOL_VERSION);
+        assert!(Controller::decode(&data[..data.len() - 1], PROTOCOL_VERSION).is_err());
+    }
+
+    #[test]
+    fn packet_round_trip() {
+        let mut stream = Vec::new();
+        write_packet(&mut stream, 2, RGBCONTROLLER_UPDATELEDS, &[1, 2, 3]).unwrap();
+        let packet = read_packet(&mut stream.as_slice()).unwrap();
+        assert_eq!(packet.device, 2);
+        assert_eq!(packet.id, RGBCONTROLLER_UPDATELEDS);
+        assert_eq!(packet.data, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn packet_with_bad_magic() {
+        let mut stream = Vec::new();
+        write_packet(&mut stream, 0, REQUEST_CONTROLLER_COUNT, &[]).unwrap();
+        stream[0] = b'X';
+        assert!(read_packet(&mut stream.as_slice()).is_err());
+    }
+
+    #[test]
+    fn packet_too_long() {
+        let mut stream = MAGIC.to_vec();
+        stream.extend_from_slice(&0u32.to_le_bytes());
+        stream.extend_from_slice(&REQUEST_CONTROLLER_DATA.to_le_bytes());
+        stream.extend_from_slice(&(MAX_PACKET_LENGTH as u32 + 1).to_le_bytes());
+        let e = read_packet(&mut stream.as_slice()).err().unwrap();
+        assert!(e.to_string().contains("too long"));
+    }
+}
@@ -0,0 +1,348 @@
+use std::io::ErrorKind;
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::*;
+use serde::Deserialize;
+
+use crate::color::Color;
+use crate::openrgb_protocol::*;
+
+/// Lets tools that speak the OpenRGB SDK protocol see our devices and set their leds, without
+/// opening the devices themselves.
+#[derive(Deserialize, Clone, Debug)]
+pub struct OpenRgbServerConfig {
+    /// Use `0.0.0.0:6742` to let other machines in.
+    #[serde(default = "default_bind")]
+    pub bind: String,
+    /// Seconds a strip keeps the colors of a client after its last update, before the color
+    /// profiles take over again. A client that disconnects lets go of its strips right away.
+    #[serde(default = "default_timeout")]
+    pub timeout: f32,
+}
+
+#[derive(Clone)]
+pub struct OpenRgbServer {
+    controllers: Arc<Mutex<Vec<Served>>>,
+    timeout: Duration,
+    clients: Arc<AtomicUsize>,
+}
+
+/// The controller for a device, with a zone for each of its strips.
+struct Served {
+    device: usize,
+    controller: Controller,
+    /// When a client last set the leds of each zone, and which client that was.
+    updated: Vec<Option<(Instant, usize)>>,
+}
+
+fn default_bind() -> String {
+    format!("127.0.0.1:{}", DEFAULT_PORT)
+}
+
+fn default_timeout() -> f32 {
+    5.0
+}
+
+impl Served {
+    fn zone_start(&self, zone: usize) -> usize {
+        self.controller.zones[..zone]
+            .iter()
+            .map(|zone| zone.leds)
+            .sum()
+    }
+
+    /// Sets the leds from `start` on, marking every zone they fall in as updated by `client`.
+    fn set(&mut self, start: usize, colors: &[Color], client: usize) {
+        let end = (start + colors.len()).min(self.controller.colors.len());
+        if start >= end {
+            return;
+        }
+        self.controller.colors[start..end].clone_from_slice(&colors[..end - start]);
+
+        let mut zone_start = 0;
+        for (zone, updated) in self.controller.zones.iter().zip(self.updated.iter_mut()) {
+            let zone_end = zone_start + zone.leds;
+            if start < zone_end && end > zone_start {
+                *updated = Some((Instant::now(), client));
+            }
+            zone_start = zone_end;
+        }
+    }
+}
+
+impl OpenRgbServer {
+    /// Serves a controller for each (device, controller) pair.
+    pub fn start(
+        config: &OpenRgbServerConfig,
+        controllers: Vec<(usize, Controller)>,
+    ) -> Result<Self> {
+        let timeout = Duration::try_from_secs_f32(config.timeout)
+            .with_context(|| format!("Invalid OpenRGB server timeout {}", config.timeout))?;
+        let listener = TcpListener::bind(&config.bind)
+            .with_context(|| format!("Unable to listen for OpenRGB clients on {}", config.bind))?;
+
+        let controllers = controllers
+            .into_iter()
+            .map(|(device, mut controller)| {
+                controller
+                    .colors
+                    .resize(controller.leds(), Color::Rgb(0.0, 0.0, 0.0));
+                Served {
+                    device,
+                    updated: vec![None; controller.zones.len()],
+                    controller,
+                }
+            })
+            .collect();
+
+        let server = Self {
+            controllers: Arc::new(Mutex::new(controllers)),
+            timeout,
+            clients: Arc::new(AtomicUsize::new(0)),
+        };
+
+        let acceptor = server.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        let client = acceptor.clone();
+                        std::thread::spawn(move || client.serve(stream));
+                    }
+                    Err(e) => log::error!("Unable to accept OpenRGB client: {}", e),
+                }
+            }
+        });
+
+        log::info!("Serving OpenRGB clients on {}", config.bind);
+        Ok(server)
+    }
+
+    fn serve(&self, mut stream: TcpStream) {
+        let client = self.clients.fetch_add(1, Ordering::Relaxed);
+        let peer = stream
+            .peer_addr()
+            .map(|address| address.to_string())
+            .unwrap_or_default();
+        log::info!("OpenRGB client {} connected", peer);
+
+        loop {
+            let packet = match read_packet(&mut stream) {
+                Ok(packet) => packet,
+                Err(e) => {
+                    match e.downcast_ref::<std::io::Error>() {
+                        Some(e) if e.kind() == ErrorKind::UnexpectedEof => {}
+                        _ => log::warn!("OpenRGB client {}: {}", peer, e),
+                    }
+                    break;
+                }
+            };
+            if let Err(e) = self.handle(&mut stream, &peer, client, packet) {
+                log::warn!("OpenRGB client {}: {}", peer, e);
+                break;
+            }
+        }
+
+        // the strips of a client that is gone don't wait for the timeout
+        let mut controllers = self.controllers.lock().unwrap();
+        for served in controllers.iter_mut() {
+            for updated in served.updated.iter_mut() {
+                if updated.is_some_and(|(_, by)| by == client) {
+                    *updated = None;
+                }
+            }
+        }
+        drop(controllers);
+
+        log::info!("OpenRGB client {} disconnected", peer);
+    }
+
+    fn handle(
+        &self,
+        stream: &mut TcpStream,
+        peer: &str,
+        client: usize,
+        packet: Packet,
+    ) -> Result<()> {
+        let mut decoder = Decoder::new(&packet.data);
+        let mut controllers = self.controllers.lock().unwrap();
+        let controller = controllers.get_mut(packet.device as usize);
+
+        match (packet.id, controller) {
+            (REQUEST_CONTROLLER_COUNT, _) => {
+                let count = controllers.len() as u32;
+                drop(controllers);
+                write_packet(stream, 0, packet.id, &count.to_le_bytes())?;
+            }
+            (REQUEST_CONTROLLER_DATA, Some(served)) => {
+                // clients of the first protocol version send no data
+                let version = decoder.u32().unwrap_or(0).min(PROTOCOL_VERSION);
+                let data = served.controller.encode(version);
+                drop(controllers);
+                write_packet(stream, packet.device, packet.id, &data)?;
+            }
+            (REQUEST_PROTOCOL_VERSION, _) => {
+                drop(controllers);
+                write_packet(stream, 0, packet.id, &PROTOCOL_VERSION.to_le_bytes())?;
+            }
+            (SET_CLIENT_NAME, _) => {
+                let name = String::from_utf8_lossy(&packet.data);
+                log::info!("OpenRGB client {} is {}", peer, name.trim_end_matches('\0'));
+            }
+            (REQUEST_PROFILE_LIST, _) => {
+                drop(controllers);
+                let mut encoder = Encoder::default();
+                encoder.u32(6).u16(0);
+                write_packet(stream, 0, packet.id, &encoder.data)?;
+            }
+            (RGBCONTROLLER_UPDATELEDS, Some(served)) => {
+                let _size = decoder.u32()?;
+                served.set(0, &decoder.colors()?, client);
+            }
+            (RGBCONTROLLER_UPDATEZONELEDS, Some(served)) => {
+                let _size = decoder.u32()?;
+                let zone = decoder.u32()? as usize;
+                let colors = decoder.colors()?;
+                if zone < served.controller.zones.len() {
+                    let leds = served.controller.zones[zone].leds;
+                    let start = served.zone_start(zone);
+                    served.set(start, &colors[..colors.len().min(leds)], client);
+                }
+            }
+            (RGBCONTROLLER_UPDATESINGLELED, Some(served)) => {
+                let led = decoder.i32()?;
+                let color = decoder.color()?;
+                if led >= 0 {
+                    served.set(led as usize, &[color], client);
+                }
+            }
+            // zones have the size of the strips, and there is only the one mode
+            (RGBCONTROLLER_RESIZEZONE, _)
+            | (RGBCONTROLLER_SETCUSTOMMODE, _)
+            | (RGBCONTROLLER_UPDATEMODE, _)
+            | (RGBCONTROLLER_SAVEMODE, _) => {}
+            (id, _) => log::debug!(
+                "Ignoring OpenRGB packet {} for controller {} from {}",
+                id,
+                packet.device,
+                peer
+            ),
+        }
+        Ok(())
+    }
+
+    /// The colors of every strip that a client updated recently, as (device, channel, colors).
+    pub fn overrides(&self) -> Vec<(usize, usize, Vec<Color>)> {
+        let controllers = self.controllers.lock().unwrap();
+        let mut overrides = Vec::new();
+        for served in controllers.iter() {
+            let mut start = 0;
+            for (channel, (zone, updated)) in served
+                .controller
+                .zones
+                .iter()
+                .zip(served.updated.iter())
+                .enumerate()
+            {
+                if updated.is_some_and(|(updated, _)| updated.elapsed() < self.timeout) {
+                    let colors = served.controller.colors[start..start + zone.leds].to_vec();
+                    overrides.push((served.device, channel, colors));
+                }
+                start += zone.leds;
+            }
+        }
+        overrides
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    fn server() -> OpenRgbServer {
+        let controller = Controller {
+            name: "Strip".to_string(),
+            zones: vec![
+                Zone {
+                    name: "Channel 1".to_string(),
+                    zone_type: ZONE_TYPE_LINEAR,
+                    leds: 2,
+                },
+                Zone {
+                    name: "Channel 2".to_string(),
+                    zone_type: ZONE_TYPE_LINEAR,
+                    leds: 3,
+                },
+            ],
+            ..Controller::default()
+        };
+        let config = OpenRgbServerConfig {
+            bind: "127.0.0.1:0".to_string(),
+            timeout: 60.0,
+        };
+        OpenRgbServer::start(&config, vec![(4, controller)]).unwrap()
+    }
+
+    /// A client connected to `server`, which serves it on a thread of its own.
+    fn connect(server: &OpenRgbServer) -> (TcpStream, std::thread::JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let server = server.clone();
+        (client, std::thread::spawn(move || server.serve(stream)))
+    }
+
+    fn wait_for(server: &OpenRgbServer, overrides: usize) -> Vec<(usize, usize, Vec<Color>)> {
+        let started = Instant::now();
+        loop {
+            let current = server.overrides();
+            if current.len() == overrides {
+                return current;
+            }
+            assert!(started.elapsed() < Duration::from_secs(2));
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn controller_data() {
+        let server = server();
+        let (mut client, _) = connect(&server);
+
+        write_packet(&mut client, 0, REQUEST_CONTROLLER_COUNT, &[]).unwrap();
+        let packet = read_packet(&mut client).unwrap();
+        assert_eq!(packet.data, 1u32.to_le_bytes());
+
+        write_packet(&mut client, 0, REQUEST_CONTROLLER_DATA, &2u32.to_le_bytes()).unwrap();
+        let packet = read_packet(&mut client).unwrap();
+        let controller = Controller::decode(&packet.data, 2).unwrap();
+        assert_eq!(controller.leds(), 5);
+    }
+
+    #[test]
+    fn overrides_last_until_the_client_disconnects() {
+        let server = server();
+        let (mut client, serving) = connect(&server);
+
+        let mut encoder = Encoder::default();
+        encoder.u32(1).colors(&[Color::Rgb(1.0, 0.0, 0.0); 3]);
+        let mut data = ((encoder.data.len() + 4) as u32).to_le_bytes().to_vec();
+        data.extend(encoder.data);
+        write_packet(&mut client, 0, RGBCONTROLLER_UPDATEZONELEDS, &data).unwrap();
+        client.flush().unwrap();
+
+        let overrides = wait_for(&server, 1);
+        assert_eq!((overrides[0].0, overrides[0].1), (4, 1));
+        let colors: Vec<_> = overrides[0].2.iter().map(encode_color).collect();
+        assert_eq!(colors, vec![[255, 0, 0, 0]; 3]);
+
+        drop(client);
+        serving.join().unwrap();
+        assert!(server.overrides().is_empty());
+    }
+}
@@ -0,0 +1,191 @@
+use std::cell::Cell;
+use std::time::Instant;
+
+use anyhow::{bail, Result};
+use serde::Deserialize;
+
+use crate::sensor::SensorRef;
+
+/// Holds a sensor at a target value by adjusting the fan duty cycle with a PID loop.
+#[derive(Deserialize, Clone, Debug)]
+pub struct PidControl {
+    pub sensor: SensorRef,
+    pub target: f32,
+    pub kp: f32,
+    #[serde(default)]
+    pub ki: f32,
+    #[serde(default)]
+    pub kd: f32,
+    #[serde(default)]
+    pub min: f32,
+    #[serde(default = "default_max")]
+    pub max: f32,
+    /// The largest change in duty cycle per second.
+    #[serde(default)]
+    pub slew: Option<f32>,
+    #[serde(skip)]
+    state: Cell<Option<PidState>>,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct PidState {
+    integral: f32,
+    error: f32,
+    output: f32,
+    time: Instant,
+}
+
+/// After this many seconds without an update the loop starts over, for example when the fan
+/// profile was inactive for a while.
+const PID_RESET_TIME: f32 = 1.0;
+
+fn default_max() -> f32 {
+    1.0
+}
+
+impl PidControl {
+    /// Checks the settings that would make the loop panic or run away.
+    pub fn validate(&self) -> Result<()> {
+        let numbers = [
+            ("target", self.target),
+            ("kp", self.kp),
+            ("ki", self.ki),
+            ("kd", self.kd),
+            ("min", self.min),
+            ("max", self.max),
+        ];
+        for (name, value) in numbers.iter() {
+            if !value.is_finite() {
+                bail!("The {} of the PID controller isn't a number", name);
+            }
+        }
+        if self.min > self.max {
+            bail!("The PID controller has min above max");
+        }
+        if let Some(slew) = self.slew {
+            if !slew.is_finite() || slew < 0.0 {
+                bail!("The slew of the PID controller must be a positive number");
+            }
+        }
+        Ok(())
+    }
+
+    /// Advance the loop with the current sensor values and return the new duty cycle.
+    pub fn update(&self, probes: &[Option<f32>]) -> f32 {
+        let value = match self.sensor.read(probes) {
+            Some(value) => value,
+            None => {
+                self.state.set(None);
+                return self.max;
+            }
+        };
+
+        // the fans need to speed up when the sensor is above its target
+        let error = value - self.target;
+        let now = Instant::now();
+
+        let state = match self.state.get() {
+            Some(state) if now.duration_since(state.time).as_secs_f32() < PID_RESET_TIME => state,
+            _ => {
+                let output = (self.kp * error).clamp(self.min, self.max);
+                self.state.set(Some(PidState {
+                    integral: 0.0,
+                    error,
+                    output,
+                    time: now,
+                }));
+                return output;
+            }
+        };
+
+        let dt = now
+            .duration_since(state.time)
+            .as_secs_f32()
+            .max(f32::EPSILON);
+        let derivative = (error - state.error) / dt;
+        let mut integral = state.integral + error * dt;
+
+        let unclamped = self.kp * error + self.ki * integral + self.kd * derivative;
+        let mut output = unclamped.clamp(self.min, self.max);
+
+        // anti-windup: stop integrating while the output is saturated in the same direction
+        if unclamped != output && (unclamped > output) == (error > 0.0) {
+            integral = state.integral;
+        }
+
+        if let Some(slew) = self.slew {
+            let step = slew * dt;
+            output = output.clamp(state.output - step, state.output + step);
+        }
+
+        self.state.set(Some(PidState {
+            integral,
+            error,
+            output,
+            time: now,
+        }));
+
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pid() -> PidControl {
+        ron::from_str(r#"(sensor: "coolant", target: 35.0, kp: 0.1, slew: Some(0.05))"#).unwrap()
+    }
+
+    #[test]
+    fn valid_settings() {
+        assert!(pid().validate().is_ok());
+    }
+
+    #[test]
+    fn negative_slew() {
+        let mut pid = pid();
+        pid.slew = Some(-0.05);
+        assert!(pid.validate().is_err());
+    }
+
+    #[test]
+    fn slew_that_isnt_a_number() {
+        let mut pid = pid();
+        pid.slew = Some(f32::NAN);
+        assert!(pid.validate().is_err());
+        pid.slew = Some(f32::INFINITY);
+        assert!(pid.validate().is_err());
+    }
+
+    #[test]
+    fn limits_that_arent_numbers() {
+        let mut pid = pid();
+        pid.min = f32::NAN;
+        assert!(pid.validate().is_err());
+
+        let mut pid = self::pid();
+        pid.max = f32::NAN;
+        assert!(pid.validate().is_err());
+    }
+
+    #[test]
+    fn min_above_max() {
+        let mut pid = pid();
+        pid.min = 0.8;
+        pid.max = 0.2;
+        assert!(pid.validate().is_err());
+    }
+
+    #[test]
+    fn gains_that_arent_numbers() {
+        for gain in 0..3 {
+            let mut pid = pid();
+            *[&mut pid.kp, &mut pid.ki, &mut pid.kd][gain] = f32::NAN;
+            assert!(pid.validate().is_err());
+        }
+        let mut pid = pid();
+        pid.target = f32::INFINITY;
+        assert!(pid.validate().is_err());
+    }
+}
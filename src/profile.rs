@@ -1,12 +1,83 @@
+use std::cell::Cell;
+use std::collections::HashMap;
+
+use anyhow::{bail, Context};
 use serde::{Deserialize, Deserializer};
 
-use crate::device::{Fan, Strip};
+use crate::adalight::AdalightConfig;
+use crate::device::{Fan, Shutdown, Strip, TempRpm};
+use crate::dmx::{DmxConfig, DmxInput};
 use crate::effect::Effect;
+use crate::filter::SensorFilter;
+use crate::hwmon::HwmonConfig;
+use crate::identity::{DeviceNames, DeviceRef};
+use crate::openrgb_client::OpenRgbClientConfig;
+use crate::openrgb_server::OpenRgbServerConfig;
+use crate::pid::PidControl;
+use crate::sensor::{SensorRef, Sensors, VirtualSensor};
+use crate::virtual_device::VirtualDeviceConfig;
+use crate::wled::WledConfig;
 
 #[derive(Deserialize, Clone, Debug)]
 pub struct Config {
     pub color_profiles: Vec<ColorProfile>,
     pub fan_profiles: Vec<FanProfile>,
+    #[serde(default)]
+    pub virtual_devices: Vec<VirtualDeviceConfig>,
+    #[serde(default)]
+    pub hwmon: Option<HwmonConfig>,
+    /// Led strips driven over a serial port with the Adalight protocol.
+    #[serde(default)]
+    pub adalight: Vec<AdalightConfig>,
+    /// Led strips on WLED nodes, streamed over the network.
+    #[serde(default)]
+    pub wled: Vec<WledConfig>,
+    /// Where DMX universes for the `Dmx` effect and the `DmxActive` trigger are received from.
+    #[serde(default)]
+    pub dmx: Option<DmxConfig>,
+    /// OpenRGB servers whose lighting is driven like our own strips.
+    #[serde(default)]
+    pub openrgb: Vec<OpenRgbClientConfig>,
+    /// Serves our devices to tools that speak the OpenRGB SDK protocol.
+    #[serde(default)]
+    pub openrgb_server: Option<OpenRgbServerConfig>,
+    /// Extra names for devices, for example `"top": "Commander PRO#2"` or `"top": "<serial>"`.
+    #[serde(default)]
+    pub device_aliases: HashMap<String, String>,
+    /// Extra names for sensors, for example `"coolant": "Commander PRO/temp1"`.
+    #[serde(default)]
+    pub sensor_aliases: HashMap<String, String>,
+    /// Sensors computed from other sensors, in order. A virtual sensor can use the ones before it.
+    #[serde(default)]
+    pub virtual_sensors: Vec<VirtualSensor>,
+    /// Smoothing, outlier rejection and fallbacks, by sensor name.
+    #[serde(default)]
+    pub sensor_filters: HashMap<String, SensorFilter>,
+    #[serde(default)]
+    pub failsafe: Failsafe,
+    /// How fans move between settings, unless a fan config overrides it.
+    #[serde(default)]
+    pub fan_transition: FanTransition,
+    /// What the lights and fans are left doing when the service exits.
+    #[serde(default)]
+    pub shutdown: Shutdown,
+}
+
+/// What to do when a fan or pump stops spinning while it should be running.
+#[derive(Deserialize, Clone, Debug)]
+pub struct Failsafe {
+    /// Zero rpm samples in a row before a fan counts as stalled.
+    #[serde(default = "default_stall_samples")]
+    pub samples: usize,
+    /// Fans driven below this duty are allowed to stand still.
+    #[serde(default = "default_stall_duty")]
+    pub min_duty: f32,
+    /// Color profile that replaces the active one while a fan is stalled.
+    #[serde(default)]
+    pub color_profile: Option<String>,
+    /// Run every other fan at full speed while a fan is stalled.
+    #[serde(default)]
+    pub max_fans: bool,
 }
 
 #[derive(Deserialize, Clone, Debug)]
@@ -27,14 +98,68 @@ pub struct FanProfile {
 
 #[derive(Deserialize, Clone, Debug)]
 pub struct FanConfig {
-    pub device: String,
+    pub device: DeviceRef,
     pub channel: usize,
-    pub config: Fan,
+    pub config: FanControl,
+    #[serde(default)]
+    pub transition: Option<FanTransition>,
+}
+
+/// Limits on how quickly a fan channel follows a change in its setting.
+#[derive(Deserialize, Clone, Copy, Debug, Default)]
+pub struct FanTransition {
+    /// Seconds it takes to move from the old speed to the new one.
+    #[serde(default)]
+    pub ramp_time: f32,
+    /// Seconds a setting is kept at least, before the channel follows the next change.
+    #[serde(default)]
+    pub min_dwell: f32,
+    /// Changes smaller than this are ignored. This is a duty cycle for pwm settings, and a
+    /// fraction of the current speed for rpm settings.
+    #[serde(default)]
+    pub hysteresis: f32,
+}
+
+/// The fan setting as written in the config. Sensors are referred to by name here, and are
+/// resolved into a `Fan` for the device that the fan belongs to.
+#[derive(Deserialize, Clone, Debug)]
+pub enum FanControl {
+    Pwm(f32),
+    Rpm(u16),
+    /// A curve that is uploaded to the device, so it can only use the device's own probes.
+    Curve(SensorRef, [TempRpm; 6]),
+    /// A curve that is evaluated every frame, so it can use any sensor and any number of points.
+    /// The points map a sensor value to a duty cycle or an rpm, depending on `output`.
+    SoftwareCurve {
+        sensor: SensorRef,
+        points: Vec<(f32, f32)>,
+        #[serde(default)]
+        output: CurveOutput,
+        #[serde(default)]
+        interpolation: Interpolation,
+    },
+    /// Holds a sensor at a target value, for example the coolant at 35 degrees.
+    Pid(PidControl),
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, Default)]
+pub enum CurveOutput {
+    #[default]
+    Pwm,
+    Rpm,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, Default)]
+pub enum Interpolation {
+    #[default]
+    Linear,
+    /// Eases in and out of every point, so the fan speed has no sudden changes in direction.
+    Smooth,
 }
 
 #[derive(Deserialize, Clone, Debug)]
 pub struct StripConfig {
-    pub device: String,
+    pub device: DeviceRef,
     pub channel: usize,
     pub indices: Indices,
     #[serde(deserialize_with="deserialize_from_maybe_file")]
@@ -48,19 +173,125 @@ pub enum Indices {
     Specific(Vec<usize>),
 }
 
+/// Sensor triggers that fired keep firing until the sensor is `hysteresis` back past the
+/// threshold, so a sensor that hovers around it doesn't flip between profiles.
 #[derive(Deserialize, Clone, Debug)]
 pub enum Trigger {
     SensorAbove {
-        sensor: usize,
-        temperature: f32,
+        sensor: SensorRef,
+        /// Called `temperature` in older configs.
+        #[serde(alias = "temperature")]
+        value: f32,
+        #[serde(default)]
+        hysteresis: f32,
+        #[serde(skip)]
+        fired: Cell<bool>,
     },
     SensorBelow {
-        sensor: usize,
-        temperature: f32,
+        sensor: SensorRef,
+        /// Called `temperature` in older configs.
+        #[serde(alias = "temperature")]
+        value: f32,
+        #[serde(default)]
+        hysteresis: f32,
+        #[serde(skip)]
+        fired: Cell<bool>,
+    },
+    /// Fires when a sensor leaves the `min..max` range, for example a voltage rail that is out
+    /// of tolerance.
+    SensorOutside {
+        sensor: SensorRef,
+        min: f32,
+        max: f32,
+        #[serde(default)]
+        hysteresis: f32,
+        #[serde(skip)]
+        fired: Cell<bool>,
     },
     ProcessRunning {
         name: String,
     },
+    /// Fires while packets for a DMX universe keep arriving, so a lighting console can take over
+    /// the strips.
+    DmxActive {
+        universe: u16,
+    },
+}
+
+impl Default for Failsafe {
+    fn default() -> Self {
+        Self {
+            samples: default_stall_samples(),
+            min_duty: default_stall_duty(),
+            color_profile: None,
+            max_fans: false,
+        }
+    }
+}
+
+fn default_stall_samples() -> usize {
+    5
+}
+
+fn default_stall_duty() -> f32 {
+    0.2
+}
+
+impl Interpolation {
+    /// Evaluate a curve of (x, y) points, sorted by x, at `x`.
+    pub fn apply(&self, points: &[(f32, f32)], x: f32) -> f32 {
+        let (first, last) = (points[0], points[points.len() - 1]);
+        if x <= first.0 {
+            return first.1;
+        }
+
+        for pair in points.windows(2) {
+            let (from, to) = (pair[0], pair[1]);
+            if x <= to.0 {
+                let t = (x - from.0) / (to.0 - from.0).max(f32::EPSILON);
+                let t = match self {
+                    Interpolation::Linear => t,
+                    Interpolation::Smooth => t * t * (3.0 - 2.0 * t),
+                };
+                return from.1 + (to.1 - from.1) * t;
+            }
+        }
+
+        last.1
+    }
+}
+
+impl FanTransition {
+    /// Whether the change from `from` to `to` is too small to follow.
+    pub fn ignores(&self, from: &Fan, to: &Fan) -> bool {
+        match (from, to) {
+            (&Fan::Pwm(from), &Fan::Pwm(to)) => (to - from).abs() < self.hysteresis,
+            (&Fan::Rpm(from), &Fan::Rpm(to)) => {
+                (to as f32 - from as f32).abs() < self.hysteresis * from as f32
+            }
+            _ => false,
+        }
+    }
+
+    /// The setting at `elapsed` seconds into the ramp from `from` to `to`. Only pwm to pwm and
+    /// rpm to rpm changes can be ramped, any other change happens at once.
+    pub fn ramp(&self, from: &Fan, to: &Fan, elapsed: f32) -> Fan {
+        let x = if self.ramp_time > 0.0 {
+            (elapsed / self.ramp_time).min(1.0)
+        } else {
+            1.0
+        };
+
+        match (from, to) {
+            (&Fan::Pwm(from), &Fan::Pwm(to)) => {
+                Fan::Pwm(((from + (to - from) * x) * 100.0).round() / 100.0)
+            }
+            (&Fan::Rpm(from), &Fan::Rpm(to)) => {
+                Fan::Rpm((from as f32 + (to as f32 - from as f32) * x).round() as u16)
+            }
+            _ => to.clone(),
+        }
+    }
 }
 
 impl ColorProfile {
@@ -73,6 +304,138 @@ impl ColorProfile {
     pub fn is_animated(&self) -> bool {
         self.strip_profiles.iter().any(|strip| strip.effect.is_animated())
     }
+
+    pub fn resolve(
+        &mut self,
+        sensors: &Sensors,
+        devices: &DeviceNames,
+        dmx: Option<&DmxInput>,
+    ) -> anyhow::Result<()> {
+        for t in self.triggers.iter_mut() {
+            t.resolve(sensors, dmx)?;
+        }
+        for p in self.strip_profiles.iter_mut() {
+            devices.resolve(&mut p.device)?;
+            for sensor in p.effect.sensors_mut() {
+                sensors.resolve(sensor)?;
+            }
+            p.effect.validate()?;
+            p.effect.connect_dmx(dmx, p.indices.indices().len())?;
+        }
+        Ok(())
+    }
+}
+
+impl FanProfile {
+    pub fn resolve(
+        &mut self,
+        sensors: &Sensors,
+        devices: &DeviceNames,
+        dmx: Option<&DmxInput>,
+    ) -> anyhow::Result<()> {
+        for t in self.triggers.iter_mut() {
+            t.resolve(sensors, dmx)?;
+        }
+        for f in self.fans.iter_mut() {
+            f.resolve(sensors, devices)?;
+        }
+        Ok(())
+    }
+}
+
+impl FanConfig {
+    pub fn resolve(&mut self, sensors: &Sensors, devices: &DeviceNames) -> anyhow::Result<()> {
+        devices.resolve(&mut self.device)?;
+        match &mut self.config {
+            FanControl::Curve(sensor, _) => {
+                sensors.resolve(sensor)?;
+                match sensors.owner(sensor) {
+                    Some((owner, _)) if self.device.is(owner) => {}
+                    _ => bail!(
+                        "Fan curve on {} uses \"{}\", but hardware curves can only use the device's own probes",
+                        self.device.name,
+                        sensor.name
+                    ),
+                }
+                if !sensors.is_curve_probe(sensor) {
+                    bail!(
+                        "Fan curve on {} uses \"{}\", but hardware curves can only follow temperature probes",
+                        self.device.name,
+                        sensor.name
+                    );
+                }
+            }
+            FanControl::SoftwareCurve { sensor, points, .. } => {
+                sensors.resolve(sensor)?;
+                if points.is_empty() {
+                    bail!("Fan curve on {} has no points", self.device.name);
+                }
+                if points.iter().any(|(x, y)| !x.is_finite() || !y.is_finite()) {
+                    bail!("Fan curve on {} has a point that isn't a number", self.device.name);
+                }
+                points.sort_by(|a, b| a.0.total_cmp(&b.0));
+            }
+            FanControl::Pid(pid) => {
+                sensors.resolve(&mut pid.sensor)?;
+                pid.validate()
+                    .with_context(|| format!("Fan controller on {}", self.device.name))?;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// The fan setting for this config on the device at `device` in the device list. Software
+    /// curves and controllers are evaluated with the current sensor values, rounded to whole
+    /// percents or rpms.
+    pub fn fan(&self, sensors: &Sensors, device: usize) -> Option<Fan> {
+        match &self.config {
+            &FanControl::Pwm(duty) => Some(Fan::Pwm(duty)),
+            &FanControl::Rpm(rpm) => Some(Fan::Rpm(rpm)),
+            FanControl::Curve(sensor, curve) => match sensors.owner(sensor) {
+                Some((owner, probe)) if owner == device => Some(Fan::Curve(probe, curve.clone())),
+                _ => None,
+            },
+            FanControl::SoftwareCurve {
+                sensor,
+                points,
+                output,
+                interpolation,
+            } => {
+                // without a reading, run at the highest speed the curve asks for
+                let value = match sensor.read(sensors.values()) {
+                    Some(value) => interpolation.apply(points, value),
+                    None => points.iter().map(|p| p.1).fold(f32::MIN, f32::max),
+                };
+                Some(match output {
+                    CurveOutput::Pwm => Fan::Pwm((value * 100.0).round() / 100.0),
+                    CurveOutput::Rpm => Fan::Rpm(value.max(0.0).round() as u16),
+                })
+            }
+            FanControl::Pid(pid) => {
+                let duty = pid.update(sensors.values());
+                Some(Fan::Pwm((duty * 100.0).round() / 100.0))
+            }
+        }
+    }
+}
+
+impl Trigger {
+    pub fn resolve(&mut self, sensors: &Sensors, dmx: Option<&DmxInput>) -> anyhow::Result<()> {
+        match self {
+            Trigger::SensorAbove { sensor, .. }
+            | Trigger::SensorBelow { sensor, .. }
+            | Trigger::SensorOutside { sensor, .. } => sensors.resolve(sensor),
+            Trigger::ProcessRunning { .. } => Ok(()),
+            &mut Trigger::DmxActive { universe } => match dmx {
+                Some(dmx) => {
+                    dmx.subscribe(universe);
+                    Ok(())
+                }
+                None => bail!("The DmxActive trigger needs the dmx settings"),
+            },
+        }
+    }
 }
 
 impl Indices {
@@ -1,7 +1,18 @@
+use std::collections::{HashMap, HashSet};
 use std::time::{Duration, Instant};
 
-use crate::device::Device;
-use crate::profile::{ColorProfile, Config, FanProfile, Trigger};
+use anyhow::*;
+
+use crate::color::Color;
+use crate::device::{Device, Fan, Shutdown};
+use crate::dmx::DmxInput;
+use crate::identity::DeviceNames;
+use crate::openrgb_protocol::{
+    Controller, Zone, DEVICE_TYPE_COOLER, DEVICE_TYPE_LEDSTRIP, ZONE_TYPE_LINEAR,
+};
+use crate::openrgb_server::OpenRgbServer;
+use crate::profile::{ColorProfile, Config, Failsafe, FanProfile, FanTransition, Trigger};
+use crate::sensor::Sensors;
 
 pub struct ProfileManager {
     devices: Vec<Box<dyn Device>>,
@@ -9,29 +20,111 @@ pub struct ProfileManager {
     color_profile_current: Option<usize>,
     fan_profiles: Vec<FanProfile>,
     fan_profile_current: Option<usize>,
+    fan_transition: FanTransition,
+    fan_outputs: HashMap<(usize, usize), FanOutput>,
+    failsafe: Failsafe,
+    failsafe_color_profile: Option<usize>,
+    shutdown: Shutdown,
+    device_names: DeviceNames,
+    dmx: Option<DmxInput>,
+    openrgb_server: Option<OpenRgbServer>,
+    /// Strips that took the colors of an OpenRGB client on the last frame, as (device, channel)
+    /// pairs.
+    openrgb_overrides: HashSet<(usize, usize)>,
+    /// Stalled fans as (device, channel) pairs.
+    stalled: Vec<(usize, usize)>,
     frame: usize,
-    probes: Vec<Option<f32>>,
+    sensors: Sensors,
     last_update: Instant,
     last_log: Instant,
 }
 
+/// A single fan channel, on its way from one setting to the next.
+struct FanOutput {
+    from: Fan,
+    target: Fan,
+    since: Instant,
+    written: Option<Fan>,
+}
+
 impl ProfileManager {
-    pub fn new(devices: Vec<Box<dyn Device>>, mut config: Config) -> Self {
+    pub fn new(mut devices: Vec<Box<dyn Device>>, mut config: Config) -> Result<Self> {
+        let device_names = DeviceNames::new(&devices, &config.device_aliases)?;
+        let sensors = Sensors::new(
+            &devices,
+            &device_names,
+            &config.sensor_aliases,
+            &config.virtual_sensors,
+            &config.sensor_filters,
+        )?;
+
+        let dmx = match config.dmx.as_ref() {
+            Some(dmx) => Some(DmxInput::start(dmx)?),
+            None => None,
+        };
+
         for p in config.color_profiles.iter_mut() {
             p.initialize();
+            p.resolve(&sensors, &device_names, dmx.as_ref())
+                .with_context(|| format!("In color profile \"{}\"", p.name))?;
+        }
+
+        for p in config.fan_profiles.iter_mut() {
+            p.resolve(&sensors, &device_names, dmx.as_ref())
+                .with_context(|| format!("In fan profile \"{}\"", p.name))?;
+        }
+
+        config
+            .shutdown
+            .resolve(&device_names)
+            .context("In the shutdown settings")?;
+
+        // strips that no color profile takes over keep showing the idle hardware effect
+        for (index, device) in devices.iter_mut().enumerate() {
+            device.idle(&config.shutdown.for_device(index));
         }
 
-        Self {
+        let openrgb_server = match config.openrgb_server.as_ref() {
+            Some(server) => {
+                let controllers =
+                    openrgb_controllers(&mut devices, &device_names, &config.color_profiles);
+                Some(OpenRgbServer::start(server, controllers)?)
+            }
+            None => None,
+        };
+
+        let failsafe_color_profile = match config.failsafe.color_profile.as_ref() {
+            Some(name) => Some(
+                config
+                    .color_profiles
+                    .iter()
+                    .position(|p| &p.name == name)
+                    .ok_or_else(|| anyhow!("Unknown failsafe color profile \"{}\"", name))?,
+            ),
+            None => None,
+        };
+
+        Ok(Self {
             devices,
             color_profiles: config.color_profiles,
             color_profile_current: None,
             fan_profiles: config.fan_profiles,
             fan_profile_current: None,
+            fan_transition: config.fan_transition,
+            fan_outputs: HashMap::new(),
+            failsafe: config.failsafe,
+            failsafe_color_profile,
+            shutdown: config.shutdown,
+            device_names,
+            dmx,
+            openrgb_server,
+            openrgb_overrides: HashSet::new(),
+            stalled: vec![],
             frame: 0,
-            probes: vec![],
+            sensors,
             last_update: Instant::now(),
             last_log: Instant::now(),
-        }
+        })
     }
 
     pub fn update(&mut self) {
@@ -48,9 +141,37 @@ impl ProfileManager {
                 }
             }
         }
+        if !self.stalled.is_empty() && self.failsafe_color_profile.is_some() {
+            next_color_profile = self.failsafe_color_profile;
+        }
+
+        // strips that an OpenRGB client let go of go back to the idle effect, and to the color
+        // profile if it covers them
+        let openrgb_overrides = self
+            .openrgb_server
+            .as_ref()
+            .map(|server| server.overrides())
+            .unwrap_or_default();
+        let overridden: HashSet<_> = openrgb_overrides
+            .iter()
+            .map(|(device, channel, _)| (*device, *channel))
+            .collect();
+        let released: Vec<_> = self
+            .openrgb_overrides
+            .difference(&overridden)
+            .cloned()
+            .collect();
+        for &(device, channel) in released.iter() {
+            let effect = self.shutdown.for_device(device).effect(channel).clone();
+            if let Some(strip) = self.devices[device].strips().get_mut(channel) {
+                strip.hardware = Some(effect);
+            }
+        }
+        self.openrgb_overrides = overridden;
 
         if next_color_profile != self.color_profile_current
             || self.color_profiles[next_color_profile.unwrap()].is_animated()
+            || !released.is_empty()
         {
             if next_color_profile != self.color_profile_current {
                 log::info!(
@@ -65,10 +186,10 @@ impl ProfileManager {
                 .strip_profiles
                 .iter_mut()
             {
-                for device in self.devices.iter_mut() {
-                    if device.name() == config.device.as_str() {
+                for (index, device) in self.devices.iter_mut().enumerate() {
+                    if config.device.is(index) {
                         if let Some(strip) = device.strips().get_mut(config.channel) {
-                            config.apply(strip, &self.probes, self.frame);
+                            config.apply(strip, self.sensors.values(), self.frame);
                         }
                     }
                 }
@@ -77,13 +198,21 @@ impl ProfileManager {
 
         // check if transient profiles should be applied
         for p in self.color_profiles.iter() {
-            if p.transient && p.triggers.iter().any(|t| self.check_trigger(t)) {
+            if !p.transient {
+                continue;
+            }
+            // every trigger is checked, so none of them misses a change of its sensor
+            let mut fired = false;
+            for t in p.triggers.iter() {
+                fired |= self.check_trigger(t);
+            }
+            if fired {
                 // apply transient color profile
                 for config in p.strip_profiles.iter() {
-                    for device in self.devices.iter_mut() {
-                        if device.name() == config.device.as_str() {
+                    for (index, device) in self.devices.iter_mut().enumerate() {
+                        if config.device.is(index) {
                             if let Some(strip) = device.strips().get_mut(config.channel) {
-                                config.apply(strip, &self.probes, self.frame);
+                                config.apply(strip, self.sensors.values(), self.frame);
                             }
                         }
                     }
@@ -91,12 +220,27 @@ impl ProfileManager {
             }
         }
 
-        // check for a new fan profile
+        // clients of the OpenRGB server have the last word while they keep sending
+        for (device, channel, colors) in openrgb_overrides {
+            if let Some(strip) = self.devices[device].strips().get_mut(channel) {
+                strip.hardware = None;
+                if strip.colors.len() < colors.len() {
+                    strip.colors.resize(colors.len(), Color::Rgb(0.0, 0.0, 0.0));
+                }
+                strip.colors[..colors.len()].clone_from_slice(&colors);
+            }
+        }
+
+        // check for a new fan profile, unless the failsafe has taken over the fans
         let mut next_fan_profile = self.fan_profile_current.or(Some(0));
-        for (i, p) in self.fan_profiles.iter().enumerate() {
-            for t in p.triggers.iter() {
-                if self.check_trigger(t) {
-                    next_fan_profile.replace(i);
+        if self.failsafe.max_fans && !self.stalled.is_empty() {
+            next_fan_profile = self.fan_profile_current;
+        } else {
+            for (i, p) in self.fan_profiles.iter().enumerate() {
+                for t in p.triggers.iter() {
+                    if self.check_trigger(t) {
+                        next_fan_profile.replace(i);
+                    }
                 }
             }
         }
@@ -107,17 +251,10 @@ impl ProfileManager {
                 self.fan_profiles[next_fan_profile.unwrap()].name
             );
             self.fan_profile_current = next_fan_profile;
+        }
 
-            // apply fan profile
-            for config in self.fan_profiles[next_fan_profile.unwrap()].fans.iter() {
-                for device in self.devices.iter_mut() {
-                    if device.name() == config.device.as_str() {
-                        if let Some(fan) = device.fans().get_mut(config.channel) {
-                            *fan = config.config.clone();
-                        }
-                    }
-                }
-            }
+        if !self.failsafe.max_fans || self.stalled.is_empty() {
+            self.apply_fan_profile();
         }
 
         // reset all devices if the loop is somehow taking longer than expected (did the system sleep?)
@@ -134,13 +271,13 @@ impl ProfileManager {
             self.last_update = Instant::now();
         }
 
-        self.probes.clear();
         for device in self.devices.iter_mut() {
             if let Err(e) = device.update() {
                 log::error!("Unable to update {}: {}", device.name(), e);
             }
-            self.probes.extend_from_slice(device.probes());
         }
+        self.sensors.update(&self.devices);
+        self.check_stalls();
 
         if self.last_log.elapsed() > Duration::from_secs(10) {
             self.last_log = Instant::now();
@@ -152,27 +289,249 @@ impl ProfileManager {
         }
     }
 
+    /// Write the settings of the active fan profile to the devices. Settings are ramped, held and
+    /// filtered as configured per channel, and only passed on to a device when they changed.
+    fn apply_fan_profile(&mut self) {
+        let profile = match self.fan_profile_current {
+            Some(current) => &self.fan_profiles[current],
+            None => return,
+        };
+
+        let now = Instant::now();
+        for config in profile.fans.iter() {
+            let transition = config.transition.unwrap_or(self.fan_transition);
+            for (index, device) in self.devices.iter_mut().enumerate() {
+                if config.device.is(index) {
+                    // a hardware curve only applies to the device that owns its sensor
+                    let setting = match config.fan(&self.sensors, index) {
+                        Some(setting) => setting,
+                        None => continue,
+                    };
+
+                    let output = self
+                        .fan_outputs
+                        .entry((index, config.channel))
+                        .or_insert_with(|| FanOutput {
+                            from: setting.clone(),
+                            target: setting.clone(),
+                            since: now,
+                            written: None,
+                        });
+
+                    let elapsed = now.duration_since(output.since).as_secs_f32();
+                    if setting != output.target
+                        && elapsed >= transition.min_dwell
+                        && !transition.ignores(&output.target, &setting)
+                    {
+                        output.from = transition.ramp(&output.from, &output.target, elapsed);
+                        output.target = setting;
+                        output.since = now;
+                    }
+
+                    let elapsed = now.duration_since(output.since).as_secs_f32();
+                    let fan = transition.ramp(&output.from, &output.target, elapsed);
+                    if output.written.as_ref() != Some(&fan) {
+                        // a device that is still missing gets the setting once it shows up
+                        if let Some(slot) = device.fans().get_mut(config.channel) {
+                            *slot = fan.clone();
+                            output.written = Some(fan);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn check_stalls(&mut self) {
+        let mut stalled = Vec::new();
+        for (index, device) in self.devices.iter().enumerate() {
+            for channel in device.stalled_fans(self.failsafe.samples, self.failsafe.min_duty) {
+                stalled.push((index, channel));
+            }
+        }
+
+        for &(index, channel) in stalled.iter() {
+            if !self.stalled.contains(&(index, channel)) {
+                log::error!(
+                    "Fan {} on {} has stalled",
+                    channel,
+                    self.device_names.display(index)
+                );
+            }
+        }
+        for &(index, channel) in self.stalled.iter() {
+            if !stalled.contains(&(index, channel)) {
+                log::info!(
+                    "Fan {} on {} is spinning again",
+                    channel,
+                    self.device_names.display(index)
+                );
+            }
+        }
+
+        if stalled.is_empty() && !self.stalled.is_empty() && self.failsafe_color_profile.is_some() {
+            // pick the color profile by its triggers again
+            self.color_profile_current = None;
+        }
+
+        if self.failsafe.max_fans && stalled != self.stalled {
+            if stalled.is_empty() {
+                // re-apply the fan profile
+                self.fan_profile_current = None;
+            } else {
+                log::warn!("Running all other fans at full speed");
+                let now = Instant::now();
+                for (index, device) in self.devices.iter_mut().enumerate() {
+                    for (channel, fan) in device.fans().iter_mut().enumerate() {
+                        if !stalled.contains(&(index, channel)) {
+                            *fan = Fan::Pwm(1.0);
+                            // ramp back down from full speed once the failsafe is over
+                            self.fan_outputs.insert(
+                                (index, channel),
+                                FanOutput {
+                                    from: Fan::Pwm(1.0),
+                                    target: Fan::Pwm(1.0),
+                                    since: now,
+                                    written: Some(Fan::Pwm(1.0)),
+                                },
+                            );
+                        }
+                    }
+                }
+            }
+        }
+
+        self.stalled = stalled;
+    }
+
     fn check_trigger(&self, trigger: &Trigger) -> bool {
         match trigger {
             &Trigger::SensorAbove {
-                sensor,
-                temperature,
-            } => self
-                .probes
-                .get(sensor)
-                .unwrap_or(&None)
-                .map(|val| val > temperature)
-                .unwrap_or_default(),
+                ref sensor,
+                value,
+                hysteresis,
+                ref fired,
+            } => {
+                let value = if fired.get() { value - hysteresis } else { value };
+                fired.set(
+                    sensor
+                        .read(self.sensors.values())
+                        .map(|val| val > value)
+                        .unwrap_or_default(),
+                );
+                fired.get()
+            }
             &Trigger::SensorBelow {
-                sensor,
-                temperature,
-            } => self
-                .probes
-                .get(sensor)
-                .unwrap_or(&None)
-                .map(|val| val < temperature)
-                .unwrap_or_default(),
+                ref sensor,
+                value,
+                hysteresis,
+                ref fired,
+            } => {
+                let value = if fired.get() { value + hysteresis } else { value };
+                fired.set(
+                    sensor
+                        .read(self.sensors.values())
+                        .map(|val| val < value)
+                        .unwrap_or_default(),
+                );
+                fired.get()
+            }
+            &Trigger::SensorOutside {
+                ref sensor,
+                min,
+                max,
+                hysteresis,
+                ref fired,
+            } => {
+                let (min, max) = if fired.get() {
+                    (min + hysteresis, max - hysteresis)
+                } else {
+                    (min, max)
+                };
+                fired.set(
+                    sensor
+                        .read(self.sensors.values())
+                        .map(|val| val < min || val > max)
+                        .unwrap_or_default(),
+                );
+                fired.get()
+            }
             &Trigger::ProcessRunning { name: _ } => false,
+            &Trigger::DmxActive { universe } => self
+                .dmx
+                .as_ref()
+                .map(|dmx| dmx.is_active(universe))
+                .unwrap_or_default(),
+        }
+    }
+}
+
+/// Dropping the manager is the one exit path, for signals and panics alike.
+impl Drop for ProfileManager {
+    fn drop(&mut self) {
+        log::info!("Handing the lights and fans back to the hardware");
+        for (index, device) in self.devices.iter_mut().enumerate() {
+            if let Err(e) = device.shutdown(&self.shutdown.for_device(index)) {
+                log::error!("Unable to shut down {}: {:?}", device.name(), e);
+            }
         }
     }
 }
+
+/// A controller for every device with led strips, with a zone for each strip. A zone is as long
+/// as the color profiles make the strip.
+fn openrgb_controllers(
+    devices: &mut [Box<dyn Device>],
+    device_names: &DeviceNames,
+    color_profiles: &[ColorProfile],
+) -> Vec<(usize, Controller)> {
+    let mut controllers = Vec::new();
+    for (index, device) in devices.iter_mut().enumerate() {
+        let led_only = device.is_led_only();
+        let serial = device.identity().unwrap_or_default();
+        let strips: Vec<Option<usize>> = device.strips().iter().map(|strip| strip.leds).collect();
+        if strips.is_empty() {
+            continue;
+        }
+
+        let zones = strips
+            .iter()
+            .enumerate()
+            .map(|(channel, known)| {
+                // strips that don't know their leds get as many as the profiles use
+                let leds = known.unwrap_or_else(|| {
+                    color_profiles
+                        .iter()
+                        .flat_map(|p| p.strip_profiles.iter())
+                        .filter(|config| config.device.is(index) && config.channel == channel)
+                        .filter_map(|config| config.indices.indices().iter().max())
+                        .map(|&led| led + 1)
+                        .max()
+                        .unwrap_or(0)
+                });
+                Zone {
+                    name: format!("Channel {}", channel + 1),
+                    zone_type: ZONE_TYPE_LINEAR,
+                    leds,
+                }
+            })
+            .collect();
+
+        controllers.push((
+            index,
+            Controller {
+                device_type: if led_only {
+                    DEVICE_TYPE_LEDSTRIP
+                } else {
+                    DEVICE_TYPE_COOLER
+                },
+                name: device_names.display(index).to_string(),
+                description: "fanservice".to_string(),
+                serial,
+                zones,
+                ..Controller::default()
+            },
+        ));
+    }
+    controllers
+}
@@ -0,0 +1,340 @@
+use std::collections::HashMap;
+use std::time::Instant;
+
+use anyhow::*;
+use serde::Deserialize;
+
+use crate::device::Device;
+use crate::filter::{FilterState, SensorFilter};
+use crate::identity::DeviceNames;
+
+/// A sensor referred to by name in the config, such as `"<serial>/temp1"` or a user alias like
+/// `"coolant"`. Any name of the device works in front of the slash. Numbered names like
+/// `"Commander PRO#2/temp1"` follow the order the devices are enumerated in, which can change
+/// between boots, so the serial number is the safer choice with several devices of a kind. The
+/// name is resolved to a position in the sensor table when the config loads.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(from = "String")]
+pub struct SensorRef {
+    pub name: String,
+    index: Option<usize>,
+}
+
+/// A sensor that is computed from other sensors.
+#[derive(Deserialize, Clone, Debug)]
+pub struct VirtualSensor {
+    pub name: String,
+    pub function: SensorFunction,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub enum SensorFunction {
+    Max(Vec<SensorRef>),
+    Min(Vec<SensorRef>),
+    Average(Vec<SensorRef>),
+    /// An average where every sensor has its own weight.
+    Weighted(Vec<(SensorRef, f32)>),
+    /// A sensor with a constant added to it.
+    Offset(SensorRef, f32),
+    /// The first sensor minus the second, such as coolant minus ambient temperature.
+    Difference(SensorRef, SensorRef),
+}
+
+/// All sensors published by the devices and all virtual sensors, with their stable names.
+pub struct Sensors {
+    names: Vec<String>,
+    /// The device that owns each probe, and the position of the probe within that device.
+    /// Sensors that aren't probes, such as fan speeds, have no owner.
+    owners: Vec<Option<(usize, usize)>>,
+    /// The number of probes of each device that hardware fan curves can follow.
+    curve_probes: Vec<usize>,
+    /// The number of probes and fans of each device when the table was made.
+    slots: Vec<(usize, usize)>,
+    /// Every name a sensor can be referred to by. `None` marks a name that is ambiguous.
+    lookup: HashMap<String, Option<usize>>,
+    virtual_sensors: Vec<VirtualSensor>,
+    filters: Vec<FilterState>,
+    values: Vec<Option<f32>>,
+}
+
+impl From<String> for SensorRef {
+    fn from(name: String) -> Self {
+        Self { name, index: None }
+    }
+}
+
+impl SensorRef {
+    pub fn read(&self, values: &[Option<f32>]) -> Option<f32> {
+        values.get(self.index?).cloned().flatten()
+    }
+}
+
+impl SensorFunction {
+    pub fn sensors_mut(&mut self) -> Vec<&mut SensorRef> {
+        match self {
+            SensorFunction::Max(sensors)
+            | SensorFunction::Min(sensors)
+            | SensorFunction::Average(sensors) => sensors.iter_mut().collect(),
+            SensorFunction::Weighted(sensors) => {
+                sensors.iter_mut().map(|(sensor, _)| sensor).collect()
+            }
+            SensorFunction::Offset(sensor, _) => vec![sensor],
+            SensorFunction::Difference(a, b) => vec![a, b],
+        }
+    }
+
+    /// Compute the value from the other sensors. Missing readings are left out of the
+    /// max, min and averages, which only go missing when all of their sensors are.
+    pub fn evaluate(&self, values: &[Option<f32>]) -> Option<f32> {
+        let read_all = |sensors: &[SensorRef]| -> Vec<f32> {
+            sensors.iter().filter_map(|s| s.read(values)).collect()
+        };
+
+        match self {
+            SensorFunction::Max(sensors) => read_all(sensors)
+                .into_iter()
+                .fold(None, |acc, v| Some(acc.map_or(v, |acc: f32| acc.max(v)))),
+            SensorFunction::Min(sensors) => read_all(sensors)
+                .into_iter()
+                .fold(None, |acc, v| Some(acc.map_or(v, |acc: f32| acc.min(v)))),
+            SensorFunction::Average(sensors) => {
+                let readings = read_all(sensors);
+                if readings.is_empty() {
+                    None
+                } else {
+                    Some(readings.iter().sum::<f32>() / readings.len() as f32)
+                }
+            }
+            SensorFunction::Weighted(sensors) => {
+                let (sum, weights) = sensors
+                    .iter()
+                    .filter_map(|(sensor, weight)| Some((sensor.read(values)?, *weight)))
+                    .fold((0.0, 0.0), |(sum, weights), (value, weight)| {
+                        (sum + value * weight, weights + weight)
+                    });
+                if weights > 0.0 {
+                    Some(sum / weights)
+                } else {
+                    None
+                }
+            }
+            SensorFunction::Offset(sensor, offset) => Some(sensor.read(values)? + offset),
+            SensorFunction::Difference(a, b) => Some(a.read(values)? - b.read(values)?),
+        }
+    }
+}
+
+impl Sensors {
+    pub fn new(
+        devices: &[Box<dyn Device>],
+        device_names: &DeviceNames,
+        aliases: &HashMap<String, String>,
+        virtual_sensors: &[VirtualSensor],
+        filters: &HashMap<String, SensorFilter>,
+    ) -> Result<Self> {
+        let mut sensors = Self {
+            names: Vec::new(),
+            owners: Vec::new(),
+            curve_probes: Vec::new(),
+            slots: Vec::new(),
+            lookup: HashMap::new(),
+            virtual_sensors: Vec::new(),
+            filters: Vec::new(),
+            values: Vec::new(),
+        };
+
+        for (device_index, device) in devices.iter().enumerate() {
+            // probes come first, followed by the fan speeds
+            let mut labels = device.probe_names();
+            let probes = labels.len();
+            let fans = device.rpms().len();
+            sensors.curve_probes.push(device.curve_probes());
+            sensors.slots.push((probes, fans));
+            labels.extend((1..=fans).map(|fan| format!("fan{}", fan)));
+            for (i, label) in labels.into_iter().enumerate() {
+                for prefix in device_names.names(device_index) {
+                    sensors.insert(format!("{}/{}", prefix, label), sensors.names.len());
+                }
+                let name = format!("{}/{}", device_names.display(device_index), label);
+                sensors.names.push(name);
+                sensors.owners.push(if i < probes {
+                    Some((device_index, i))
+                } else {
+                    None
+                });
+            }
+        }
+
+        // aliases can name virtual sensors, and virtual sensors can use aliases, so aliases are
+        // added as soon as the sensor they refer to exists
+        let mut pending: Vec<_> = aliases.iter().collect();
+        sensors.insert_aliases(&mut pending);
+
+        for sensor in virtual_sensors.iter() {
+            let mut sensor = sensor.clone();
+            let name = sensor.name.clone();
+            for input in sensor.function.sensors_mut() {
+                sensors
+                    .resolve(input)
+                    .with_context(|| format!("In virtual sensor \"{}\"", name))?;
+            }
+            sensors.insert(sensor.name.clone(), sensors.names.len());
+            sensors.names.push(sensor.name.clone());
+            sensors.owners.push(None);
+            sensors.virtual_sensors.push(sensor);
+            sensors.insert_aliases(&mut pending);
+        }
+
+        // anything left over refers to a sensor that doesn't exist
+        for (alias, name) in pending {
+            sensors
+                .lookup(name)
+                .with_context(|| format!("Invalid alias \"{}\"", alias))?;
+        }
+
+        sensors.filters = sensors
+            .names
+            .iter()
+            .map(|_| FilterState::default())
+            .collect();
+        let mut filtered = vec![None; sensors.names.len()];
+        for (name, filter) in filters.iter() {
+            let index = sensors
+                .lookup(name)
+                .with_context(|| format!("Invalid sensor filter \"{}\"", name))?;
+            if let Some(other) = filtered[index].replace(name) {
+                bail!(
+                    "Sensor filters \"{}\" and \"{}\" are for the same sensor",
+                    other,
+                    name
+                );
+            }
+            sensors.filters[index] = FilterState::new(filter.clone());
+        }
+
+        sensors.values = vec![None; sensors.names.len()];
+
+        log::info!("Sensors: {:?}", sensors.names);
+
+        Ok(sensors)
+    }
+
+    fn insert(&mut self, name: String, index: usize) {
+        self.lookup
+            .entry(name)
+            .and_modify(|existing| {
+                if *existing != Some(index) {
+                    *existing = None
+                }
+            })
+            .or_insert(Some(index));
+    }
+
+    fn insert_aliases(&mut self, pending: &mut Vec<(&String, &String)>) {
+        pending.retain(|(alias, name)| match self.lookup(name) {
+            Ok(index) => {
+                self.insert((*alias).clone(), index);
+                false
+            }
+            Err(_) => true,
+        });
+    }
+
+    fn lookup(&self, name: &str) -> Result<usize> {
+        match self.lookup.get(name) {
+            Some(&Some(index)) => Ok(index),
+            Some(None) => bail!("Sensor name \"{}\" is ambiguous", name),
+            None => bail!("Unknown sensor \"{}\"", name),
+        }
+    }
+
+    pub fn resolve(&self, sensor: &mut SensorRef) -> Result<()> {
+        sensor.index = Some(self.lookup(&sensor.name)?);
+        Ok(())
+    }
+
+    /// The device that owns a resolved probe, and the position of the probe within that device.
+    pub fn owner(&self, sensor: &SensorRef) -> Option<(usize, usize)> {
+        self.owners.get(sensor.index?).cloned().flatten()
+    }
+
+    /// Whether a hardware fan curve on the device that owns `sensor` can follow it.
+    pub fn is_curve_probe(&self, sensor: &SensorRef) -> bool {
+        self.owner(sensor)
+            .is_some_and(|(device, probe)| probe < self.curve_probes[device])
+    }
+
+    pub fn values(&self) -> &[Option<f32>] {
+        &self.values
+    }
+
+    pub fn update(&mut self, devices: &[Box<dyn Device>]) {
+        let now = Instant::now();
+        self.values.clear();
+        // a device that reports more or fewer values than it had keeps its slots, so the sensors
+        // of the devices after it stay where they are
+        for (device, &(probes, fans)) in devices.iter().zip(self.slots.iter()) {
+            let readings = device.probes();
+            self.values
+                .extend((0..probes).map(|i| readings.get(i).cloned().flatten()));
+            let rpms = device.rpms();
+            self.values
+                .extend((0..fans).map(|i| rpms.get(i).cloned().flatten().map(|rpm| rpm as f32)));
+        }
+        for (i, value) in self.values.iter_mut().enumerate() {
+            *value = self.filters[i].apply(&self.names[i], *value, now);
+        }
+
+        // virtual sensors see the filtered values, and are filtered themselves
+        for sensor in self.virtual_sensors.iter() {
+            let index = self.values.len();
+            let value = sensor.function.evaluate(&self.values);
+            let value = self.filters[index].apply(&self.names[index], value, now);
+            self.values.push(value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::virtual_device::{VirtualDevice, VirtualDeviceConfig, VirtualProbe};
+
+    use super::*;
+
+    fn device(name: &str, probes: Vec<VirtualProbe>) -> Box<dyn Device> {
+        let mut device = VirtualDevice::new(VirtualDeviceConfig {
+            name: name.to_string(),
+            serial: None,
+            fans: vec![],
+            strips: 0,
+            probes,
+            probe_names: vec![],
+        });
+        device.update().unwrap();
+        Box::new(device)
+    }
+
+    #[test]
+    fn device_that_grows_keeps_its_slots() {
+        let devices = vec![
+            device("Grows", vec![]),
+            device("Other", vec![VirtualProbe::Constant(30.0)]),
+        ];
+        let names = DeviceNames::new(&devices, &HashMap::new()).unwrap();
+        let mut sensors =
+            Sensors::new(&devices, &names, &HashMap::new(), &[], &HashMap::new()).unwrap();
+
+        let mut other = SensorRef::from("Other/temp1".to_string());
+        sensors.resolve(&mut other).unwrap();
+        sensors.update(&devices);
+        assert_eq!(other.read(sensors.values()), Some(30.0));
+
+        let devices = vec![
+            device("Grows", vec![VirtualProbe::Constant(50.0); 7]),
+            device("Other", vec![VirtualProbe::Constant(30.0)]),
+        ];
+        sensors.update(&devices);
+        assert_eq!(sensors.values(), &[Some(30.0)]);
+        assert_eq!(other.read(sensors.values()), Some(30.0));
+    }
+}
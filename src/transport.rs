@@ -0,0 +1,135 @@
+#[cfg(test)]
+use std::cell::{Cell, RefCell};
+#[cfg(test)]
+use std::collections::VecDeque;
+#[cfg(test)]
+use std::rc::Rc;
+
+use anyhow::{bail, Result};
+use hidapi::HidDevice;
+
+/// The raw report channel a device driver talks through. This is implemented for `HidDevice`,
+/// and by `MockTransport` so drivers can be exercised without hardware.
+pub trait Transport {
+    fn write(&self, data: &[u8]) -> Result<usize>;
+
+    /// Read a report, or return 0 when nothing arrives within `timeout` milliseconds.
+    fn read_timeout(&self, buf: &mut [u8], timeout: i32) -> Result<usize>;
+}
+
+impl Transport for HidDevice {
+    fn write(&self, data: &[u8]) -> Result<usize> {
+        Ok(HidDevice::write(self, data)?)
+    }
+
+    fn read_timeout(&self, buf: &mut [u8], timeout: i32) -> Result<usize> {
+        Ok(HidDevice::read_timeout(self, buf, timeout)?)
+    }
+}
+
+impl<T: Transport + ?Sized> Transport for Box<T> {
+    fn write(&self, data: &[u8]) -> Result<usize> {
+        T::write(self, data)
+    }
+
+    fn read_timeout(&self, buf: &mut [u8], timeout: i32) -> Result<usize> {
+        T::read_timeout(self, buf, timeout)
+    }
+}
+
+/// The transport of a device that isn't plugged in. Every write and read fails.
+pub struct Unplugged;
+
+impl Transport for Unplugged {
+    fn write(&self, _data: &[u8]) -> Result<usize> {
+        bail!("Device is unplugged");
+    }
+
+    fn read_timeout(&self, _buf: &mut [u8], _timeout: i32) -> Result<usize> {
+        bail!("Device is unplugged");
+    }
+}
+
+/// An in-memory transport. Every written report is recorded, and a write that starts with a
+/// registered prefix queues the matching canned response for the next read.
+#[cfg(test)]
+#[derive(Default)]
+pub struct MockTransport {
+    written: RefCell<Vec<Vec<u8>>>,
+    responses: RefCell<Vec<(Vec<u8>, Vec<u8>)>>,
+    pending: RefCell<VecDeque<Vec<u8>>>,
+    unplugged: Cell<bool>,
+}
+
+#[cfg(test)]
+impl MockTransport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Answer every report starting with `prefix` with `response`.
+    /// Later registrations take precedence over earlier ones.
+    pub fn respond(&self, prefix: &[u8], response: &[u8]) {
+        self.responses
+            .borrow_mut()
+            .push((prefix.to_vec(), response.to_vec()));
+    }
+
+    /// Fail every write and read from now on, like a device that was pulled out.
+    pub fn unplug(&self) {
+        self.unplugged.set(true);
+    }
+
+    /// Take all reports written so far, leaving the record empty.
+    pub fn take_written(&self) -> Vec<Vec<u8>> {
+        std::mem::take(&mut *self.written.borrow_mut())
+    }
+}
+
+#[cfg(test)]
+impl Transport for MockTransport {
+    fn write(&self, data: &[u8]) -> Result<usize> {
+        if self.unplugged.get() {
+            bail!("Device is unplugged");
+        }
+        self.written.borrow_mut().push(data.to_vec());
+
+        let responses = self.responses.borrow();
+        if let Some((_, response)) = responses
+            .iter()
+            .rev()
+            .find(|(prefix, _)| data.starts_with(prefix))
+        {
+            self.pending.borrow_mut().push_back(response.clone());
+        }
+
+        Ok(data.len())
+    }
+
+    /// Canned responses are there right away, so nothing is ever waited for.
+    fn read_timeout(&self, buf: &mut [u8], _timeout: i32) -> Result<usize> {
+        if self.unplugged.get() {
+            bail!("Device is unplugged");
+        }
+        match self.pending.borrow_mut().pop_front() {
+            Some(response) => {
+                let len = response.len().min(buf.len());
+                buf[..len].copy_from_slice(&response[..len]);
+                Ok(len)
+            }
+            None => Ok(0),
+        }
+    }
+}
+
+/// Lets a test keep a handle on the transport it hands to a driver.
+#[cfg(test)]
+impl<T: Transport> Transport for Rc<T> {
+    fn write(&self, data: &[u8]) -> Result<usize> {
+        T::write(self, data)
+    }
+
+    fn read_timeout(&self, buf: &mut [u8], timeout: i32) -> Result<usize> {
+        T::read_timeout(self, buf, timeout)
+    }
+}
@@ -0,0 +1,241 @@
+use std::time::Instant;
+
+use anyhow::Result;
+use serde::Deserialize;
+
+use crate::device::{curve_rpm, stalled, Device, Fan, Shutdown, StallCounter, Strip};
+
+/// A device that only exists in software. It can stand in for a Commander PRO or a Lighting Node
+/// CORE by using the same name, so the daemon and its profiles can be run without any hardware.
+pub struct VirtualDevice {
+    config: VirtualDeviceConfig,
+    fans: Vec<Fan>,
+    strips: Vec<Strip>,
+    probes: Vec<Option<f32>>,
+    rpms: Vec<f32>,
+    stalls: Vec<StallCounter>,
+    started: Instant,
+    last_update: Instant,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct VirtualDeviceConfig {
+    pub name: String,
+    /// Stands in for the serial number of a real device.
+    #[serde(default)]
+    pub serial: Option<String>,
+    #[serde(default)]
+    pub fans: Vec<VirtualFan>,
+    #[serde(default)]
+    pub strips: usize,
+    #[serde(default)]
+    pub probes: Vec<VirtualProbe>,
+    /// Names for the probes, such as `"12v"` to mimic the Commander PRO voltage rails.
+    /// Probes without a name are called `tempN`.
+    #[serde(default)]
+    pub probe_names: Vec<String>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct VirtualFan {
+    pub max_rpm: u16,
+    /// Time in seconds it takes the fan to get most of the way to a new speed.
+    #[serde(default)]
+    pub response: f32,
+    /// A failed fan never spins, no matter what it is told to do.
+    #[serde(default)]
+    pub failed: bool,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub enum VirtualProbe {
+    Disconnected,
+    Constant(f32),
+    /// A list of (seconds, temperature) points that is interpolated linearly.
+    Script {
+        points: Vec<(f32, f32)>,
+        #[serde(default)]
+        repeat: bool,
+    },
+}
+
+impl VirtualDevice {
+    pub fn new(config: VirtualDeviceConfig) -> Self {
+        Self {
+            fans: vec![Fan::Pwm(0.25); config.fans.len()],
+            strips: vec![Strip::default(); config.strips],
+            probes: vec![None; config.probes.len()],
+            rpms: vec![0.0; config.fans.len()],
+            stalls: vec![StallCounter::default(); config.fans.len()],
+            started: Instant::now(),
+            last_update: Instant::now(),
+            config,
+        }
+    }
+
+    fn target_rpm(&self, index: usize) -> f32 {
+        let fan = &self.config.fans[index];
+        if fan.failed {
+            return 0.0;
+        }
+
+        let rpm = match &self.fans[index] {
+            &Fan::Pwm(duty) => duty.clamp(0.0, 1.0) * fan.max_rpm as f32,
+            &Fan::Rpm(rpm) => rpm as f32,
+            Fan::Curve(sensor, curve) => self
+                .probes
+                .get(*sensor)
+                .cloned()
+                .flatten()
+                .map(|temp| curve_rpm(curve, temp))
+                .unwrap_or(fan.max_rpm as f32),
+        };
+
+        rpm.min(fan.max_rpm as f32)
+    }
+}
+
+impl VirtualProbe {
+    fn sample(&self, time: f32) -> Option<f32> {
+        match self {
+            VirtualProbe::Disconnected => None,
+            &VirtualProbe::Constant(temp) => Some(temp),
+            VirtualProbe::Script { points, repeat } => {
+                let (first, last) = (points.first()?, points.last()?);
+                let time = if *repeat && last.0 > first.0 {
+                    first.0 + (time - first.0).rem_euclid(last.0 - first.0)
+                } else {
+                    time
+                };
+
+                if time <= first.0 {
+                    return Some(first.1);
+                }
+
+                for pair in points.windows(2) {
+                    let (from, to) = (pair[0], pair[1]);
+                    if time <= to.0 {
+                        let x = (time - from.0) / (to.0 - from.0).max(f32::EPSILON);
+                        return Some(from.1 + (to.1 - from.1) * x);
+                    }
+                }
+
+                Some(last.1)
+            }
+        }
+    }
+}
+
+impl Device for VirtualDevice {
+    fn initialize(&mut self) -> Result<()> {
+        let time = self.started.elapsed().as_secs_f32();
+        for (probe, config) in self.probes.iter_mut().zip(self.config.probes.iter()) {
+            *probe = config.sample(time);
+        }
+
+        log::info!(
+            "{} (virtual): \n Fans: {} \n Strips: {} \n Temperature: {:?}",
+            self.config.name,
+            self.fans.len(),
+            self.strips.len(),
+            self.probes
+        );
+
+        Ok(())
+    }
+
+    fn is_led_only(&self) -> bool {
+        self.fans.is_empty() && self.probes.is_empty()
+    }
+
+    fn name(&self) -> &str {
+        self.config.name.as_str()
+    }
+
+    fn identity(&self) -> Option<String> {
+        self.config.serial.clone()
+    }
+
+    fn fans(&mut self) -> &mut [Fan] {
+        &mut self.fans
+    }
+
+    fn strips(&mut self) -> &mut [Strip] {
+        &mut self.strips
+    }
+
+    fn probes(&self) -> &[Option<f32>] {
+        &self.probes
+    }
+
+    fn probe_names(&self) -> Vec<String> {
+        (1..=self.probes.len())
+            .map(|i| match self.config.probe_names.get(i - 1) {
+                Some(name) => name.clone(),
+                None => format!("temp{}", i),
+            })
+            .collect()
+    }
+
+    fn rpms(&self) -> Vec<Option<u16>> {
+        self.rpms.iter().map(|&rpm| Some(rpm as u16)).collect()
+    }
+
+    fn stalled_fans(&self, samples: usize, min_duty: f32) -> Vec<usize> {
+        stalled(&self.stalls, samples, min_duty)
+    }
+
+    fn report_status(&self) {
+        log::info!(
+            target: format!("{} status", self.config.name).as_str(),
+            "temperatures = {:?}, fan speeds = {:?}",
+            self.probes,
+            self.rpms.iter().map(|&rpm| rpm as u16).collect::<Vec<_>>()
+        )
+    }
+
+    fn update(&mut self) -> Result<()> {
+        let dt = std::mem::replace(&mut self.last_update, Instant::now())
+            .elapsed()
+            .as_secs_f32();
+        let time = self.started.elapsed().as_secs_f32();
+
+        for (probe, config) in self.probes.iter_mut().zip(self.config.probes.iter()) {
+            *probe = config.sample(time);
+        }
+
+        for i in 0..self.fans.len() {
+            let target = self.target_rpm(i);
+            let response = self.config.fans[i].response;
+            let x = if response > 0.0 {
+                (dt / response).min(1.0)
+            } else {
+                1.0
+            };
+            self.rpms[i] += (target - self.rpms[i]) * x;
+            self.stalls[i].sample(&self.fans[i], self.rpms[i] as u16);
+        }
+
+        Ok(())
+    }
+
+    fn shutdown(&mut self, shutdown: &Shutdown) -> Result<()> {
+        for fan in self.fans.iter_mut() {
+            *fan = match self.probes.iter().position(Option::is_some) {
+                Some(probe) => Fan::Curve(probe, shutdown.fan_curve.clone()),
+                None => Fan::Pwm(shutdown.fan_duty),
+            };
+        }
+
+        log::info!(
+            "{} (virtual): {} fans on the safe curve, strips running {:?}",
+            self.config.name,
+            self.fans.len(),
+            (0..self.strips.len())
+                .map(|i| shutdown.effect(i))
+                .collect::<Vec<_>>()
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,437 @@
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::mpsc::{channel, Receiver, TryRecvError};
+use std::time::{Duration, Instant};
+
+use anyhow::*;
+use serde::Deserialize;
+
+use crate::color::Color;
+use crate::device::{Device, Fan, HardwareEffect, Shutdown, Strip};
+
+/// A led strip on a WLED node, or anything else that takes DDP, streamed over UDP.
+pub struct Wled {
+    config: WledConfig,
+    socket: Option<UdpSocket>,
+    /// The address the host name was last resolved to.
+    address: Option<SocketAddr>,
+    /// The result of the lookup that is under way.
+    resolving: Option<Receiver<Result<SocketAddr>>>,
+    strips: Vec<Strip>,
+    strips_dirty: bool,
+    last_send: Instant,
+    last_attempt: Option<Instant>,
+    sequence: u8,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct WledConfig {
+    pub name: String,
+    /// A host name or ip address, with an optional port.
+    pub address: String,
+    pub leds: usize,
+    #[serde(default)]
+    pub protocol: WledProtocol,
+    /// Seconds WLED waits after the last frame before it goes back to its own effects. Only the
+    /// realtime protocol sends this, with DDP it is set on the node itself.
+    #[serde(default = "default_timeout")]
+    pub timeout: f32,
+    /// Seconds after which an unchanged frame is sent again, so the node stays in realtime mode.
+    #[serde(default = "default_keepalive")]
+    pub keepalive: f32,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, Default)]
+pub enum WledProtocol {
+    #[default]
+    Ddp,
+    /// WLED's own realtime UDP protocol, in its DNRGB form.
+    Realtime,
+}
+
+const DDP_PORT: u16 = 4048;
+const DDP_VERSION: u8 = 0x40;
+const DDP_PUSH: u8 = 0x01;
+const DDP_TYPE_RGB24: u8 = 0x0b;
+const DDP_DEFAULT_OUTPUT: u8 = 0x01;
+const DDP_MAX_LEDS: usize = 480;
+
+const REALTIME_PORT: u16 = 21324;
+const REALTIME_DNRGB: u8 = 4;
+const REALTIME_MAX_LEDS: usize = 489;
+/// A timeout of 255 keeps WLED in realtime mode until it is told otherwise.
+const REALTIME_NO_TIMEOUT: u8 = 255;
+
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(5);
+
+fn default_timeout() -> f32 {
+    2.5
+}
+
+fn default_keepalive() -> f32 {
+    1.0
+}
+
+fn rgb(colors: &[Color]) -> Vec<u8> {
+    colors
+        .iter()
+        .flat_map(|color| {
+            let [r, g, b] = color.rgb();
+            vec![(r * 255.0) as u8, (g * 255.0) as u8, (b * 255.0) as u8]
+        })
+        .collect()
+}
+
+/// Looks up a host name or ip address, with an optional port. This can take a while, so it
+/// happens on a thread of its own.
+fn resolve(address: &str, port: u16) -> Result<SocketAddr> {
+    match address.to_socket_addrs() {
+        Ok(mut addresses) => addresses.next(),
+        // no port was given
+        Err(_) => (address, port).to_socket_addrs()?.next(),
+    }
+    .ok_or_else(|| anyhow!("Unable to resolve {}", address))
+}
+
+/// DDP packets carry a byte offset, and only the last one of a frame tells the node to show it.
+fn ddp_packets(colors: &[Color], sequence: u8) -> Vec<Vec<u8>> {
+    let chunks = colors.chunks(DDP_MAX_LEDS).count();
+    colors
+        .chunks(DDP_MAX_LEDS)
+        .enumerate()
+        .map(|(i, chunk)| {
+            let data = rgb(chunk);
+            let last = i + 1 == chunks;
+            let mut packet = vec![
+                DDP_VERSION | if last { DDP_PUSH } else { 0 },
+                sequence,
+                DDP_TYPE_RGB24,
+                DDP_DEFAULT_OUTPUT,
+            ];
+            packet.extend_from_slice(&((i * DDP_MAX_LEDS * 3) as u32).to_be_bytes());
+            packet.extend_from_slice(&(data.len() as u16).to_be_bytes());
+            packet.extend(data);
+            packet
+        })
+        .collect()
+}
+
+/// DNRGB packets carry the index of their first led.
+fn realtime_packets(colors: &[Color], timeout: u8) -> Vec<Vec<u8>> {
+    colors
+        .chunks(REALTIME_MAX_LEDS)
+        .enumerate()
+        .map(|(i, chunk)| {
+            let mut packet = vec![REALTIME_DNRGB, timeout];
+            packet.extend_from_slice(&((i * REALTIME_MAX_LEDS) as u16).to_be_bytes());
+            packet.extend(rgb(chunk));
+            packet
+        })
+        .collect()
+}
+
+impl WledProtocol {
+    fn port(&self) -> u16 {
+        match self {
+            WledProtocol::Ddp => DDP_PORT,
+            WledProtocol::Realtime => REALTIME_PORT,
+        }
+    }
+}
+
+impl Wled {
+    pub fn new(config: WledConfig) -> Self {
+        Self {
+            strips: vec![Strip {
+                leds: Some(config.leds),
+                ..Strip::default()
+            }],
+            strips_dirty: true,
+            socket: None,
+            address: None,
+            resolving: None,
+            last_send: Instant::now(),
+            last_attempt: None,
+            sequence: 0,
+            config,
+        }
+    }
+
+    /// Starts looking up the address in the background. An ip address needs no lookup.
+    fn start_resolving(&mut self) {
+        self.last_attempt = Some(Instant::now());
+        let port = self.config.protocol.port();
+        if let Ok(address) = self.config.address.parse::<SocketAddr>() {
+            self.address = Some(address);
+            return;
+        }
+        if let Ok(ip) = self.config.address.parse::<IpAddr>() {
+            self.address = Some(SocketAddr::new(ip, port));
+            return;
+        }
+
+        let (sender, receiver) = channel();
+        let address = self.config.address.clone();
+        std::thread::spawn(move || sender.send(resolve(&address, port)));
+        self.resolving = Some(receiver);
+    }
+
+    /// Picks up the address once a lookup finishes.
+    fn check_resolved(&mut self) -> Result<()> {
+        let result = match self
+            .resolving
+            .as_ref()
+            .map(|resolving| resolving.try_recv())
+        {
+            None | Some(Err(TryRecvError::Empty)) => return Ok(()),
+            Some(Err(TryRecvError::Disconnected)) => {
+                self.resolving = None;
+                return Ok(());
+            }
+            Some(Ok(result)) => result,
+        };
+        self.resolving = None;
+
+        let address = result?;
+        if self.address != Some(address) {
+            self.address = Some(address);
+            self.socket = None;
+        }
+        Ok(())
+    }
+
+    fn connect(&mut self) -> Result<()> {
+        let address = self
+            .address
+            .ok_or_else(|| anyhow!("{} is not resolved yet", self.config.address))?;
+        let socket = UdpSocket::bind(("0.0.0.0", 0))?;
+        socket.connect(address)?;
+        self.socket = Some(socket);
+        Ok(())
+    }
+
+    /// The packets that make up a frame.
+    fn packets(&mut self, colors: &[Color], timeout: u8) -> Vec<Vec<u8>> {
+        match self.config.protocol {
+            WledProtocol::Ddp => {
+                // sequence numbers run from 1 to 15, 0 means they aren't used
+                self.sequence = self.sequence % 15 + 1;
+                ddp_packets(colors, self.sequence)
+            }
+            WledProtocol::Realtime => realtime_packets(colors, timeout),
+        }
+    }
+
+    fn send(&mut self, colors: &[Color], timeout: u8) -> Result<()> {
+        if self.socket.is_none() {
+            self.connect()?;
+        }
+        for packet in self.packets(colors, timeout) {
+            let socket = self.socket.as_ref().unwrap();
+            if let Err(e) = socket.send(&packet) {
+                // look the address up again, in case it changed
+                self.socket = None;
+                if self.resolving.is_none() {
+                    self.start_resolving();
+                }
+                return Err(e)
+                    .with_context(|| format!("Unable to send to {}", self.config.address));
+            }
+        }
+
+        self.last_send = Instant::now();
+        Ok(())
+    }
+}
+
+impl Device for Wled {
+    /// Host names are looked up in the background. One that can't be resolved yet, such as a
+    /// node that isn't on the network, is tried again later.
+    fn initialize(&mut self) -> Result<()> {
+        if self.resolving.is_none() {
+            self.start_resolving();
+        }
+        self.strips_dirty = true;
+        Ok(())
+    }
+
+    fn is_led_only(&self) -> bool {
+        true
+    }
+
+    fn name(&self) -> &str {
+        self.config.name.as_str()
+    }
+
+    fn fans(&mut self) -> &mut [Fan] {
+        &mut []
+    }
+
+    fn strips(&mut self) -> &mut [Strip] {
+        self.strips_dirty = true;
+        &mut self.strips
+    }
+
+    fn probes(&self) -> &[Option<f32>] {
+        &[]
+    }
+
+    fn report_status(&self) {}
+
+    fn update(&mut self) -> Result<()> {
+        if !self.strips_dirty && self.last_send.elapsed().as_secs_f32() < self.config.keepalive {
+            return Ok(());
+        }
+
+        // hardware effects are left to the node itself, once it times out
+        match self.strips[0].hardware {
+            None | Some(HardwareEffect::Static(_)) => {}
+            Some(_) => {
+                self.strips_dirty = false;
+                return Ok(());
+            }
+        }
+
+        self.check_resolved()?;
+        if self.address.is_none() {
+            let waiting = self
+                .last_attempt
+                .is_some_and(|last| last.elapsed() < RECONNECT_INTERVAL);
+            if self.resolving.is_none() && !waiting {
+                self.start_resolving();
+            }
+            return Ok(());
+        }
+
+        let colors = self.strips[0].render(self.config.leds);
+        let timeout = self.config.timeout.clamp(1.0, 254.0).round() as u8;
+        self.send(&colors, timeout)?;
+        self.strips_dirty = false;
+        Ok(())
+    }
+
+    /// The node goes back to its own effects when we stop sending, unless the strip should be
+    /// left on a static color. DDP has no way to keep the colors once the node times out, so that
+    /// last frame always goes over WLED's realtime protocol, which can tell the node to hold it.
+    fn shutdown(&mut self, shutdown: &Shutdown) -> Result<()> {
+        if let HardwareEffect::Static(color) = shutdown.effect(0) {
+            let colors = vec![*color; self.config.leds];
+            match self.config.protocol {
+                WledProtocol::Realtime => self.send(&colors, REALTIME_NO_TIMEOUT)?,
+                WledProtocol::Ddp => {
+                    let mut address = self
+                        .address
+                        .ok_or_else(|| anyhow!("{} is not resolved yet", self.config.address))?;
+                    address.set_port(REALTIME_PORT);
+                    let socket = UdpSocket::bind(("0.0.0.0", 0))?;
+                    for packet in realtime_packets(&colors, REALTIME_NO_TIMEOUT) {
+                        socket
+                            .send_to(&packet, address)
+                            .with_context(|| format!("Unable to send to {}", address))?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A node on a local port, and a strip of `leds` that sends to it.
+    fn node(protocol: WledProtocol, leds: usize) -> (UdpSocket, Wled) {
+        let listener = UdpSocket::bind("127.0.0.1:0").unwrap();
+        listener
+            .set_read_timeout(Some(Duration::from_secs(2)))
+            .unwrap();
+        let mut device = Wled::new(WledConfig {
+            name: "Test".to_string(),
+            address: listener.local_addr().unwrap().to_string(),
+            leds,
+            protocol,
+            timeout: 2.5,
+            keepalive: default_keepalive(),
+        });
+        device.initialize().unwrap();
+        device.strips()[0].colors = vec![Color::Rgb(1.0, 0.0, 0.0); leds];
+        (listener, device)
+    }
+
+    fn receive(listener: &UdpSocket) -> Vec<u8> {
+        let mut buf = [0u8; 2048];
+        let len = listener.recv(&mut buf).unwrap();
+        buf[..len].to_vec()
+    }
+
+    #[test]
+    fn host_names_are_resolved_in_the_background() {
+        let local = ("localhost", 0).to_socket_addrs().unwrap().next().unwrap();
+        let listener = UdpSocket::bind(local).unwrap();
+        listener
+            .set_read_timeout(Some(Duration::from_secs(2)))
+            .unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mut device = Wled::new(WledConfig {
+            name: "Test".to_string(),
+            address: format!("localhost:{}", port),
+            leds: 1,
+            protocol: WledProtocol::Ddp,
+            timeout: 2.5,
+            keepalive: default_keepalive(),
+        });
+        device.initialize().unwrap();
+
+        let started = Instant::now();
+        while device.address.is_none() {
+            assert!(started.elapsed() < Duration::from_secs(2));
+            std::thread::sleep(Duration::from_millis(10));
+            device.update().unwrap();
+        }
+        device.update().unwrap();
+        assert_eq!(receive(&listener)[..4], [0x41, 1, 0x0b, 0x01]);
+    }
+
+    #[test]
+    fn ddp_frame() {
+        let (listener, mut device) = node(WledProtocol::Ddp, 3);
+        device.update().unwrap();
+
+        let packet = receive(&listener);
+        assert_eq!(packet[..10], [0x41, 1, 0x0b, 0x01, 0, 0, 0, 0, 0, 9]);
+        assert_eq!(packet[10..], [255, 0, 0, 255, 0, 0, 255, 0, 0]);
+
+        // the next frame has the next sequence number
+        device.strips()[0].colors.clear();
+        device.update().unwrap();
+        assert_eq!(receive(&listener)[1], 2);
+    }
+
+    #[test]
+    fn ddp_pushes_the_last_chunk() {
+        let (listener, mut device) = node(WledProtocol::Ddp, DDP_MAX_LEDS + 20);
+        device.update().unwrap();
+
+        let first = receive(&listener);
+        assert_eq!(first[..10], [0x40, 1, 0x0b, 0x01, 0, 0, 0, 0, 0x05, 0xa0]);
+        assert_eq!(first.len(), 10 + DDP_MAX_LEDS * 3);
+
+        let second = receive(&listener);
+        assert_eq!(second[..10], [0x41, 1, 0x0b, 0x01, 0, 0, 0x05, 0xa0, 0, 60]);
+        assert_eq!(second.len(), 10 + 60);
+    }
+
+    #[test]
+    fn realtime_offsets() {
+        let (listener, mut device) = node(WledProtocol::Realtime, REALTIME_MAX_LEDS + 11);
+        device.update().unwrap();
+
+        let first = receive(&listener);
+        assert_eq!(first[..4], [REALTIME_DNRGB, 3, 0, 0]);
+        assert_eq!(first.len(), 4 + REALTIME_MAX_LEDS * 3);
+
+        let second = receive(&listener);
+        assert_eq!(second[..4], [REALTIME_DNRGB, 3, 0x01, 0xe9]);
+        assert_eq!(second[4..], [255, 0, 0].repeat(11)[..]);
+    }
+}